@@ -1,11 +1,19 @@
 // smartifier2.rs
 
-use clap::{Arg, ArgAction, Command};
+use clap::parser::ValueSource;
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use regex::Regex;
+use serde::Deserialize;
 use serde_json::{Map, Value};
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fs::File;
 use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::ops::Range;
 use std::path::Path;
+use std::rc::Rc;
+use std::sync::Arc;
 use std::time::Instant;
 
 // -----------------------------------------------------------------------------
@@ -14,31 +22,207 @@ use std::time::Instant;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum DataType {
-    CSV,
-    JSONL,
+    Csv,
+    Jsonl,
 }
 
-#[derive(Debug)]
+/// Options for vertex mode.
+#[derive(Clone)]
+struct VertexOptions {
+    smart_attr: String,
+    data_type: DataType,
+    csv: CsvDialect,
+    write_key: bool,
+    smart_value: String,
+    smart_index: i32,
+    // if set, the smart graph attribute value is extracted from the smart
+    // value (or the key) with this regular expression
+    smart_regex: Option<Regex>,
+    // if set, the smart graph attribute value and the key are computed with
+    // these expressions, see `parse_template`
+    smart_expr: Option<Expr>,
+    key_expr: Option<Expr>,
+    // if set, the smart graph attribute value is put into this bucket
+    smart_bucket: Option<Bucket>,
+    // if set, the smart graph attribute value is mapped with this table
+    smart_map: Option<Arc<SmartMap>>,
+    // if set, the smart value is the key of a parent vertex and replaced
+    // with the smart graph attribute value of that parent
+    parent_lookup: Option<Rc<ParentLookup>>,
+    // if set, the key prefix is computed with `enterprise_prefix` and no
+    // smart graph attribute is written
+    enterprise: bool,
+    smart_default: String,
+    key_value: String,
+    // collection name used when rewriting `_id`, if empty, the collection
+    // name is taken from the existing `_id` value
+    id_collection: String,
+    drop_id: bool,
+    column_renames: Vec<(usize, String)>,
+    // if false, the CSV input has no header line
+    header: bool,
+    // if not empty, the names of the CSV columns, replacing the header line
+    columns: Vec<String>,
+    output: OutputFormat,
+}
+
+// The output file is only used by the `graph` and `run` subcommands. The
+// optional settings override the global options for this collection.
+#[derive(Debug, Default)]
 struct VertexCollection {
     coll_name: String,
     file_name: String,
+    output_file: Option<String>,
+    data_type: Option<DataType>,
+    sep: Option<String>,
+    quo: Option<char>,
+    esc: Option<EscapeStyle>,
+    header: Option<bool>,
+    columns: Option<Vec<String>>,
+    smart_value: Option<String>,
+    smart_index: Option<i32>,
+    smart_regex: Option<Regex>,
+    smart_expr: Option<Expr>,
+    key_expr: Option<Expr>,
+    smart_bucket: Option<Bucket>,
+    key_value: Option<String>,
+    id_collection: Option<String>,
+    column_renames: Option<Vec<(usize, String)>>,
+    // attributes with references to other vertices, see `--vertex-ref`
+    references: Vec<VertexRef>,
+    // a satellite collection in a hybrid smart graph, its keys are not
+    // smartified
+    satellite: bool,
 }
 
-#[derive(Debug)]
+impl VertexCollection {
+    /// Returns the given CSV dialect with the settings of this collection applied.
+    fn csv_dialect(&self, global: &CsvDialect) -> CsvDialect {
+        CsvDialect {
+            sep: self.sep.clone().unwrap_or_else(|| global.sep.clone()),
+            quo: self.quo.unwrap_or(global.quo),
+            esc: self.esc.unwrap_or(global.esc),
+        }
+    }
+
+    /// Returns the global options with the settings of this collection applied.
+    fn options(&self, global: &VertexOptions) -> VertexOptions {
+        let mut opts = global.clone();
+        opts.data_type = self.data_type.unwrap_or(opts.data_type);
+        opts.csv = self.csv_dialect(&opts.csv);
+        opts.header = self.header.unwrap_or(opts.header);
+        opts.smart_index = self.smart_index.unwrap_or(opts.smart_index);
+        if let Some(smart_regex) = &self.smart_regex {
+            opts.smart_regex = Some(smart_regex.clone());
+        }
+        if let Some(smart_expr) = &self.smart_expr {
+            opts.smart_expr = Some(smart_expr.clone());
+        }
+        if let Some(key_expr) = &self.key_expr {
+            opts.key_expr = Some(key_expr.clone());
+        }
+        if let Some(smart_bucket) = &self.smart_bucket {
+            opts.smart_bucket = Some(smart_bucket.clone());
+        }
+        if let Some(smart_value) = &self.smart_value {
+            opts.smart_value = smart_value.clone();
+        }
+        if let Some(key_value) = &self.key_value {
+            opts.key_value = key_value.clone();
+        }
+        if let Some(id_collection) = &self.id_collection {
+            opts.id_collection = id_collection.clone();
+        }
+        if let Some(columns) = &self.columns {
+            opts.columns = columns.clone();
+        }
+        if let Some(renames) = &self.column_renames {
+            opts.column_renames = renames.clone();
+        }
+        opts
+    }
+}
+
+// The permitted vertex collections for `_from` and `_to`, the first one
+// is used for values without a collection prefix. The optional settings
+// override the global options for this collection.
+#[derive(Debug, Default)]
 struct EdgeCollection {
     file_name: String,
-    from_vertex_coll: String,
-    to_vertex_coll: String,
+    from_vertex_colls: Vec<String>,
+    to_vertex_colls: Vec<String>,
     column_renames: Vec<(usize, String)>,
+    data_type: Option<DataType>,
+    sep: Option<String>,
+    quo: Option<char>,
+    esc: Option<EscapeStyle>,
+    header: Option<bool>,
+    columns: Option<Vec<String>>,
+    from_attr: Option<String>,
+    to_attr: Option<String>,
+}
+
+impl EdgeCollection {
+    /// Returns the given CSV dialect with the settings of this collection applied.
+    fn csv_dialect(&self, global: &CsvDialect) -> CsvDialect {
+        CsvDialect {
+            sep: self.sep.clone().unwrap_or_else(|| global.sep.clone()),
+            quo: self.quo.unwrap_or(global.quo),
+            esc: self.esc.unwrap_or(global.esc),
+        }
+    }
+
+    /// Returns the global options with the settings of this collection applied.
+    fn options(&self, global: &EdgeOptions) -> EdgeOptions {
+        let mut opts = global.clone();
+        opts.data_type = self.data_type.unwrap_or(opts.data_type);
+        opts.csv = self.csv_dialect(&opts.csv);
+        opts.header = self.header.unwrap_or(opts.header);
+        if let Some(columns) = &self.columns {
+            opts.columns = columns.clone();
+        }
+        if let Some(from_attr) = &self.from_attr {
+            opts.from_attr = from_attr.clone();
+        }
+        if let Some(to_attr) = &self.to_attr {
+            opts.to_attr = to_attr.clone();
+        }
+        opts
+    }
+}
+
+/// What to do if the same `<coll>/<key>` is found more than once in the
+/// vertex files with different smart graph attribute values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConflictPolicy {
+    FirstWins,
+    LastWins,
+    Error,
+}
+
+// One entry in the key table: the index of the smart attribute value and
+// where the vertex was found (index into `sources` and line number), such
+// that conflicts can be reported with both locations.
+#[derive(Debug, Clone, Copy)]
+struct KeyEntry {
+    att: u32,
+    source: u32,
+    line: u64,
 }
 
 // A structure corresponding to the C++ "Translation" struct,
 // storing the mapping from "key -> attribute index" and from "attribute -> index".
 #[derive(Default)]
 struct Translation {
-    key_tab: HashMap<String, u32>,
+    key_tab: HashMap<String, KeyEntry>,
     att_tab: HashMap<String, u32>,
     smart_attributes: Vec<String>,
+    sources: Vec<String>,
+    collections: HashSet<String>,
+    // satellite vertex collections, their keys are used as they are
+    satellites: HashSet<String>,
+    conflicts: u64,
+    not_smart: u64,
     mem_usage: usize,
 }
 
@@ -58,71 +242,346 @@ fn elapsed() -> f64 {
     0.0
 }
 
+// -----------------------------------------------------------------------------
+// Translation table for edge mode
+// -----------------------------------------------------------------------------
+
+impl Translation {
+    /// Looks up the smart graph attribute value for a full `<coll>/<key>`.
+    fn smart_attribute(&self, full_key: &str) -> Option<&str> {
+        self.key_tab
+            .get(full_key)
+            .map(|e| self.smart_attributes[e.att as usize].as_str())
+    }
+
+    fn location(&self, source: u32, line: u64) -> String {
+        format!("{} line {}", self.sources[source as usize], line)
+    }
+
+    /// Returns the index of a smart attribute value, adding it if needed.
+    fn att_index(&mut self, att: &str) -> u32 {
+        if let Some(&idx) = self.att_tab.get(att) {
+            return idx;
+        }
+        let idx = self.smart_attributes.len() as u32;
+        self.smart_attributes.push(att.to_string());
+        self.att_tab.insert(att.to_string(), idx);
+        self.mem_usage += 2 * att.len() + 32;
+        idx
+    }
+
+    /// Registers a vertex `<coll>/<key>` with its smart attribute value.
+    /// A conflict is reported and counted, with `ConflictPolicy::Error` the
+    /// first value is kept, such that all conflicts can be reported before
+    /// the caller aborts.
+    fn insert(
+        &mut self,
+        full_key: &str,
+        att: &str,
+        source: u32,
+        line: u64,
+        policy: ConflictPolicy,
+    ) {
+        let att_idx = self.att_index(att);
+        let entry = KeyEntry {
+            att: att_idx,
+            source,
+            line,
+        };
+        let Some(&old) = self.key_tab.get(full_key) else {
+            self.mem_usage += full_key.len() + std::mem::size_of::<KeyEntry>() + 32;
+            self.key_tab.insert(full_key.to_string(), entry);
+            return;
+        };
+        if old.att == att_idx {
+            return;
+        }
+        self.conflicts += 1;
+        eprintln!(
+            "Conflicting smart graph attribute for vertex {}: '{}' in {} and '{}' in {}",
+            full_key,
+            self.smart_attributes[old.att as usize],
+            self.location(old.source, old.line),
+            att,
+            self.location(source, line)
+        );
+        if policy == ConflictPolicy::LastWins {
+            self.key_tab.insert(full_key.to_string(), entry);
+        }
+    }
+
+    /// Registers a vertex file, returns its index in `sources`.
+    fn add_source(&mut self, coll_name: &str, file_name: &str) -> u32 {
+        self.sources.push(file_name.to_string());
+        self.collections.insert(coll_name.to_string());
+        (self.sources.len() - 1) as u32
+    }
+
+    /// Registers a satellite vertex collection, whose vertices need not
+    /// be read since their keys stay as they are.
+    fn add_satellite(&mut self, coll_name: &str) {
+        self.collections.insert(coll_name.to_string());
+        self.satellites.insert(coll_name.to_string());
+    }
+
+    /// Adds a smartified vertex `_key` (`<smart>:<key>`) of collection
+    /// `coll_name`. Keys without a smart prefix are counted and ignored.
+    fn add_vertex_key(
+        &mut self,
+        coll_name: &str,
+        key: &str,
+        source: u32,
+        line: u64,
+        policy: ConflictPolicy,
+    ) {
+        let Some(colon_pos) = key.find(':') else {
+            self.not_smart += 1;
+            return;
+        };
+        let full_key = format!("{}/{}", coll_name, &key[colon_pos + 1..]);
+        self.insert(&full_key, &key[..colon_pos], source, line, policy)
+    }
+
+    /// Prints statistics about the table after all vertices were added.
+    fn report(&self) {
+        println!(
+            "{:.3} Translation table has {} keys and {} smart values (~{} MiB).",
+            elapsed(),
+            self.key_tab.len(),
+            self.smart_attributes.len(),
+            self.mem_usage / (1024 * 1024)
+        );
+        if self.not_smart > 0 {
+            eprintln!(
+                "Warning: {} vertices have no smart _key, ignoring them.",
+                self.not_smart
+            );
+        }
+        if self.conflicts > 0 {
+            eprintln!(
+                "Warning: found {} conflicting vertex definitions.",
+                self.conflicts
+            );
+        }
+    }
+
+    /// Returns the exit code 5 if conflicts were found and the policy is
+    /// `ConflictPolicy::Error`, all of them were reported by `insert`.
+    fn abort_on_conflicts(&self, policy: ConflictPolicy) -> Option<i32> {
+        if policy != ConflictPolicy::Error || self.conflicts == 0 {
+            return None;
+        }
+        eprintln!(
+            "Aborting because of {} conflicting vertex definitions.",
+            self.conflicts
+        );
+        Some(5)
+    }
+
+    /// Reads a (smartified) vertex file and adds all its keys to the table.
+    /// The smart graph attribute value is taken from the prefix of `_key`.
+    /// The file format is given by `opts` unless set for the collection.
+    /// Lookup files are smartified outputs, so they have a header line
+    /// unless the collection says otherwise, whatever `--no-header` says.
+    fn load_vertices(
+        &mut self,
+        coll: &VertexCollection,
+        opts: &EdgeOptions,
+        policy: ConflictPolicy,
+    ) -> i32 {
+        let data_type = coll.data_type.unwrap_or(opts.data_type);
+        let csv = &coll.csv_dialect(&opts.csv);
+        let header = coll.header.unwrap_or(true);
+        println!(
+            "{:.3} Reading vertices of collection {} from {}",
+            elapsed(),
+            coll.coll_name,
+            coll.file_name
+        );
+        let input = match File::open(&coll.file_name) {
+            Ok(f) => f,
+            Err(e) => {
+                eprintln!("Cannot open vertex file {}: {}", coll.file_name, e);
+                return 1;
+            }
+        };
+        let source = self.add_source(&coll.coll_name, &coll.file_name);
+        let mut lines = BufReader::new(input).lines();
+
+        let mut key_pos: Option<usize> = None;
+        let mut first_line = None;
+        if data_type == DataType::Csv {
+            let columns = coll.columns.as_deref().unwrap_or_default();
+            let Some((col_headers, first)) = read_csv_header(&mut lines, header, columns, csv)
+            else {
+                eprintln!(
+                    "Could not read header line in vertex file {}",
+                    coll.file_name
+                );
+                return 3;
+            };
+            first_line = first;
+            let pos = find_col_pos(&col_headers, "_key");
+            if pos < 0 {
+                eprintln!("Did not find _key column in vertex file {}", coll.file_name);
+                return 4;
+            }
+            key_pos = Some(pos as usize);
+        }
+
+        let mut line_nr: u64 = if key_pos.is_some() && header { 1 } else { 0 };
+        for line_result in first_line.map(Ok).into_iter().chain(lines) {
+            line_nr += 1;
+            let Ok(line_str) = line_result else {
+                continue;
+            };
+            let key = match key_pos {
+                Some(pos) => {
+                    let parts = split(&line_str, csv);
+                    match parts.get(pos) {
+                        Some(k) => unquote(k, csv),
+                        None => continue,
+                    }
+                }
+                None => match serde_json::from_str::<Value>(&line_str) {
+                    Ok(v) => match v.get("_key") {
+                        Some(Value::String(k)) => k.clone(),
+                        _ => continue,
+                    },
+                    Err(e) => {
+                        eprintln!(
+                            "JSON parse error in file {}, line {}: {}",
+                            coll.file_name, line_nr, e
+                        );
+                        continue;
+                    }
+                },
+            };
+            self.add_vertex_key(&coll.coll_name, &key, source, line_nr, policy);
+            if line_nr.is_multiple_of(1_000_000) {
+                println!(
+                    "{:.3} Have read {} vertices from {} ...",
+                    elapsed(),
+                    line_nr,
+                    coll.file_name
+                );
+            }
+        }
+        0
+    }
+}
+
 // -----------------------------------------------------------------------------
 // CSV-related helper functions
 // -----------------------------------------------------------------------------
 
+/// How quote characters (and other special characters) are escaped in CSV.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EscapeStyle {
+    // a quote character inside a quoted field is doubled: `"a""b"`
+    Double,
+    // a backslash escapes the next character: `"a\"b"` or `a\,b`
+    Backslash,
+    // no escaping, a quote character always ends a quoted field
+    None,
+}
+
+/// The CSV dialect of a file, the separator can have multiple characters.
+#[derive(Debug, Clone)]
+struct CsvDialect {
+    sep: String,
+    quo: char,
+    esc: EscapeStyle,
+}
+
 /// Splits a line by a given separator, taking quotes into account.
-/// This is a manual approach similar to the C++ version.
-fn split(line: &str, sep: char, quo: char) -> Vec<String> {
+/// This is a manual approach similar to the C++ version. The fields are
+/// returned as they are, that is, still quoted and escaped.
+fn split(line: &str, csv: &CsvDialect) -> Vec<String> {
     let mut result = Vec::new();
     let mut start = 0;
-    let mut pos = 0;
     let mut in_quote = false;
+    let mut chars = line.char_indices().peekable();
 
-    let add = |pos: usize, start: &mut usize, result: &mut Vec<String>| {
-        result.push(line[*start..pos].to_string());
-        *start = pos + 1;
-    };
-
-    while pos < line.len() {
-        let c = line.chars().nth(pos).unwrap();
+    while let Some((pos, c)) = chars.next() {
+        if c == '\\' && csv.esc == EscapeStyle::Backslash {
+            // skip the escaped character, wherever it is
+            chars.next();
+            continue;
+        }
         if !in_quote {
-            if c == quo {
+            if c == csv.quo {
                 in_quote = true;
-                pos += 1;
-                continue;
-            }
-            if c == sep {
-                add(pos, &mut start, &mut result);
-                pos += 1;
-                continue;
-            }
-            pos += 1;
-        } else {
-            // in_quote == true
-            if c == quo {
-                // check if it's a double quote
-                if pos + 1 < line.len() && line.chars().nth(pos + 1).unwrap() == quo {
-                    // skip both quotes
-                    pos += 2;
-                    continue;
+            } else if line[pos..].starts_with(csv.sep.as_str()) {
+                result.push(line[start..pos].to_string());
+                start = pos + csv.sep.len();
+                // skip the rest of a multi-character separator
+                while chars.peek().is_some_and(|(p, _)| *p < start) {
+                    chars.next();
                 }
-                in_quote = false;
-                pos += 1;
+            }
+        } else if c == csv.quo {
+            // check if it's a double quote
+            if csv.esc == EscapeStyle::Double && chars.peek().is_some_and(|(_, n)| *n == csv.quo) {
+                // skip both quotes
+                chars.next();
                 continue;
             }
-            pos += 1;
+            in_quote = false;
         }
     }
 
     // add the last field
-    add(pos, &mut start, &mut result);
+    result.push(line[start..].to_string());
 
     result
 }
 
-/// Removes surrounding quotes and handles double quotes inside.
-fn unquote(s: &str, quo: char) -> String {
-    // If there is no quote char at all, return as-is:
-    if !s.contains(quo) {
+/// Returns the character for a backslash escape sequence like `\n`.
+fn unescape_char(c: char) -> char {
+    match c {
+        'n' => '\n',
+        'r' => '\r',
+        't' => '\t',
+        '0' => '\0',
+        _ => c,
+    }
+}
+
+/// Removes surrounding quotes and handles escaped quotes inside.
+fn unquote(s: &str, csv: &CsvDialect) -> String {
+    let quo = csv.quo;
+    let backslash = csv.esc == EscapeStyle::Backslash;
+    // If there is nothing to unquote or unescape, return as-is:
+    if !(s.contains(quo) || backslash && s.contains('\\')) {
         return s.to_string();
     }
 
+    // MySQL writes NULL as an unquoted `\N`, which becomes an empty field
+    if backslash && s == "\\N" {
+        return String::new();
+    }
+
+    let chars: Vec<char> = s.chars().collect();
+    if backslash && chars.first() != Some(&quo) {
+        // an unquoted field, in which only backslash escapes are resolved
+        let mut res = String::new();
+        let mut pos = 0;
+        while pos < chars.len() {
+            if chars[pos] == '\\' && pos + 1 < chars.len() {
+                res.push(unescape_char(chars[pos + 1]));
+                pos += 2;
+            } else {
+                res.push(chars[pos]);
+                pos += 1;
+            }
+        }
+        return res;
+    }
+
     // We mimic the logic from the C++ version
     let mut res = String::new();
     let mut pos = 0;
-    let chars: Vec<char> = s.chars().collect();
     // Find the first quote:
     while pos < chars.len() && chars[pos] != quo {
         pos += 1;
@@ -136,8 +595,14 @@ fn unquote(s: &str, quo: char) -> String {
     let mut in_quote = true;
     while pos < chars.len() {
         if in_quote {
+            if backslash && chars[pos] == '\\' && pos + 1 < chars.len() {
+                res.push(unescape_char(chars[pos + 1]));
+                pos += 2;
+                continue;
+            }
             if chars[pos] == quo {
-                if pos + 1 < chars.len() && chars[pos + 1] == quo {
+                if csv.esc == EscapeStyle::Double && pos + 1 < chars.len() && chars[pos + 1] == quo
+                {
                     // double quote, produce one
                     res.push(quo);
                     pos += 2;
@@ -147,35 +612,81 @@ fn unquote(s: &str, quo: char) -> String {
             } else {
                 res.push(chars[pos]);
             }
-        } else {
-            if chars[pos] == quo {
-                in_quote = true;
-            }
+        } else if chars[pos] == quo {
+            in_quote = true;
         }
         pos += 1;
     }
     res
 }
 
-/// If a string contains the quote character, wrap and double it appropriately.
-fn quote_string(s: &str, quo: char) -> String {
-    if !s.contains(quo) {
+/// If a string contains the quote character or the separator, wrap it in
+/// quotes and escape it according to the dialect. Without an escape style
+/// a quote character inside the string cannot be represented and is
+/// written as it is.
+fn quote_string(s: &str, csv: &CsvDialect) -> String {
+    let quo = csv.quo;
+    let backslash = csv.esc == EscapeStyle::Backslash;
+    let needs_quotes = s.contains(quo)
+        || s.contains(csv.sep.as_str())
+        || (backslash && s.contains(['\\', '\n', '\r']));
+    if !needs_quotes {
         return s.to_string();
     }
     let mut res = String::new();
     res.push(quo);
     for c in s.chars() {
-        if c == quo {
-            res.push(quo);
-            res.push(quo);
-        } else {
-            res.push(c);
+        match (csv.esc, c) {
+            (EscapeStyle::Double, _) if c == quo => {
+                res.push(quo);
+                res.push(quo);
+            }
+            (EscapeStyle::Backslash, '\n') => res.push_str("\\n"),
+            (EscapeStyle::Backslash, '\r') => res.push_str("\\r"),
+            (EscapeStyle::Backslash, _) if c == quo || c == '\\' => {
+                res.push('\\');
+                res.push(c);
+            }
+            _ => res.push(c),
         }
     }
     res.push(quo);
     res
 }
 
+/// Reads the column names from the first line of a CSV file. If the file
+/// has no header, the columns are named by their position and the first
+/// line is returned as well, since it still has to be processed as data.
+/// If `columns` is not empty, these names are used instead, a header line
+/// is then skipped.
+fn read_csv_header(
+    lines: &mut impl Iterator<Item = std::io::Result<String>>,
+    header: bool,
+    columns: &[String],
+    csv: &CsvDialect,
+) -> Option<(Vec<String>, Option<String>)> {
+    let first_line = match lines.next() {
+        Some(line) => Some(line.ok()?),
+        None if !header && !columns.is_empty() => None,
+        None => return None,
+    };
+    let col_headers = match (&first_line, columns.is_empty()) {
+        (Some(line), true) => split(line, csv)
+            .into_iter()
+            .map(|s| unquote(&s, csv))
+            .collect::<Vec<String>>(),
+        _ => columns.to_vec(),
+    };
+    if header {
+        Some((col_headers, None))
+    } else if columns.is_empty() {
+        let names = (0..col_headers.len()).map(|i| i.to_string()).collect();
+        Some((names, first_line))
+    } else {
+        Some((col_headers, first_line))
+    }
+}
+
 /// Finds the position of a column in a header vector. Returns -1 if not found.
 fn find_col_pos(col_headers: &[String], header: &str) -> i32 {
     match col_headers.iter().position(|h| h == header) {
@@ -185,96 +696,1020 @@ fn find_col_pos(col_headers: &[String], header: &str) -> i32 {
 }
 
 // -----------------------------------------------------------------------------
-// CSV transformations for vertices (mimicking the C++ version)
+// Template expressions for smart values and keys
 // -----------------------------------------------------------------------------
 
-fn transform_vertex_csv(
-    line: &str,
-    count: u64,
-    sep: char,
-    quo: char,
-    ncols: usize,
-    smart_attr_pos: i32,
-    smart_value_pos: i32,
-    smart_index: i32,
-    key_pos: i32,
-    key_value_pos: i32,
-    out: &mut dyn Write,
-) {
-    let mut parts = split(line, sep, quo);
-    // Extend with empty columns if needed
-    while parts.len() < ncols {
-        parts.push(String::new());
-    }
-    // Also ensure if smart_attr_pos or key_pos are out-of-range, add empty
-    if smart_attr_pos as usize >= parts.len() {
-        parts.push(String::new());
-    }
-    if key_pos as usize >= parts.len() {
-        parts.push(String::new());
+/// A parsed expression for `--smart-expr` and `--key-expr`, which is
+/// evaluated for every vertex to a string.
+#[derive(Debug, Clone)]
+enum Expr {
+    Literal(String),
+    // An integer, which must not be a column name, see `Expr::bind_columns`
+    Number(String),
+    // A column or attribute by name, with the attribute path for JSONL
+    Field(String, Vec<String>),
+    // A CSV column by position, see `Expr::bind_columns`
+    Column(usize),
+    Upper(Box<Expr>),
+    Lower(Box<Expr>),
+    Trim(Box<Expr>),
+    // start and optional length in characters
+    Substr(Box<Expr>, usize, Option<usize>),
+    // width, fill character and whether to pad on the left
+    Pad(Box<Expr>, usize, char, bool),
+    Concat(Vec<Expr>),
+    // latitude and longitude or a GeoJSON point, and the precision
+    Geohash(Vec<Expr>, usize),
+}
+
+/// The record an expression is evaluated against.
+enum Record<'a> {
+    Csv(&'a [String], &'a CsvDialect),
+    Json(&'a Map<String, Value>),
+}
+
+impl Expr {
+    /// Evaluates the expression, a missing column or attribute is empty.
+    fn eval(&self, rec: &Record) -> String {
+        match self {
+            Expr::Literal(s) | Expr::Number(s) => s.clone(),
+            Expr::Field(_, path) => match rec {
+                Record::Json(obj) => match get_path(obj, path) {
+                    Some(Value::String(s)) => s.clone(),
+                    Some(Value::Null) | None => String::new(),
+                    Some(v) => v.to_string(),
+                },
+                Record::Csv(..) => String::new(),
+            },
+            Expr::Column(pos) => match rec {
+                Record::Csv(parts, csv) => {
+                    parts.get(*pos).map(|s| unquote(s, csv)).unwrap_or_default()
+                }
+                Record::Json(_) => String::new(),
+            },
+            Expr::Upper(e) => e.eval(rec).to_uppercase(),
+            Expr::Lower(e) => e.eval(rec).to_lowercase(),
+            Expr::Trim(e) => e.eval(rec).trim().to_string(),
+            Expr::Substr(e, start, len) => {
+                let s = e.eval(rec);
+                let chars = s.chars().skip(*start);
+                match len {
+                    Some(len) => chars.take(*len).collect(),
+                    None => chars.collect(),
+                }
+            }
+            Expr::Pad(e, width, fill, left) => {
+                let s = e.eval(rec);
+                let n = width.saturating_sub(s.chars().count());
+                let padding: String = std::iter::repeat_n(*fill, n).collect();
+                if *left {
+                    padding + &s
+                } else {
+                    s + &padding
+                }
+            }
+            Expr::Concat(parts) => parts.iter().map(|e| e.eval(rec)).collect(),
+            Expr::Geohash(args, precision) => {
+                let coords = match args.as_slice() {
+                    [lat, lon] => lat
+                        .eval(rec)
+                        .trim()
+                        .parse()
+                        .ok()
+                        .zip(lon.eval(rec).trim().parse().ok()),
+                    [point] => point_coordinates(&point.eval(rec)),
+                    _ => None,
+                };
+                match coords {
+                    Some((lat, lon))
+                        if (-90.0..=90.0).contains(&lat) && (-180.0..=180.0).contains(&lon) =>
+                    {
+                        geohash(lat, lon, *precision)
+                    }
+                    _ => String::new(),
+                }
+            }
+        }
     }
 
-    // Find the smart graph attribute value
-    let att = if smart_value_pos >= 0 && (smart_value_pos as usize) < parts.len() {
-        let mut val = unquote(&parts[smart_value_pos as usize], quo);
-        if smart_index > 0 && (val.len() as i32) > smart_index {
-            val = val[..smart_index as usize].to_string();
+    /// Replaces column names by their positions in the CSV header. An
+    /// integer which is also a column name, like in a file without header
+    /// line, is an error, since `col(<name>)` is meant.
+    fn bind_columns(&mut self, col_headers: &[String]) -> Result<(), String> {
+        match self {
+            Expr::Number(n) => {
+                if find_col_pos(col_headers, n) >= 0 {
+                    return Err(format!(
+                        "Number {} in expression is also a column name, use col({}) for the column",
+                        n, n
+                    ));
+                }
+            }
+            Expr::Field(name, _) => {
+                let pos = find_col_pos(col_headers, name);
+                if pos < 0 {
+                    return Err(format!("Column {} in expression not found", name));
+                }
+                *self = Expr::Column(pos as usize);
+            }
+            Expr::Upper(e)
+            | Expr::Lower(e)
+            | Expr::Trim(e)
+            | Expr::Substr(e, ..)
+            | Expr::Pad(e, ..) => e.bind_columns(col_headers)?,
+            Expr::Concat(parts) | Expr::Geohash(parts, _) => {
+                for e in parts {
+                    e.bind_columns(col_headers)?;
+                }
+            }
+            Expr::Literal(_) | Expr::Column(_) => {}
         }
-        parts[smart_attr_pos as usize] = quote_string(&val, quo);
-        val
-    } else {
-        unquote(&parts[smart_attr_pos as usize], quo)
-    };
+        Ok(())
+    }
+}
 
-    // Now handle the key
-    let key = if key_value_pos >= 0 && (key_value_pos as usize) < parts.len() {
-        unquote(&parts[key_value_pos as usize], quo)
-    } else {
-        unquote(&parts[key_pos as usize], quo)
+/// Builds a geohash expression from one GeoJSON point or from latitude
+/// and longitude, the precision is the number of characters.
+fn geohash_expr(args: Vec<Expr>, precision: usize) -> Result<Expr, String> {
+    if !(1..=12).contains(&precision) {
+        return Err("The precision of a geohash must be a number from 1 to 12.".to_string());
+    }
+    if args.is_empty() || args.len() > 2 {
+        return Err("A geohash needs a GeoJSON point or latitude and longitude.".to_string());
+    }
+    Ok(Expr::Geohash(args, precision))
+}
+
+/// Reads latitude and longitude from a GeoJSON point like
+/// `{"type":"Point","coordinates":[<lon>,<lat>]}` or a `[<lon>,<lat>]`
+/// array.
+fn point_coordinates(s: &str) -> Option<(f64, f64)> {
+    let val: Value = serde_json::from_str(s).ok()?;
+    let coords = match &val {
+        Value::Object(obj) => obj.get("coordinates")?,
+        _ => &val,
     };
+    match coords.as_array()?.as_slice() {
+        [lon, lat, ..] => Some((lat.as_f64()?, lon.as_f64()?)),
+        _ => None,
+    }
+}
 
-    let split_pos = key.find(':');
-    if split_pos.is_none() {
-        // not yet transformed
-        parts[key_pos as usize] = quote_string(&(att.clone() + ":" + &key), quo);
-    } else {
-        // already has a colon
-        let colon_pos = split_pos.unwrap();
-        let prefix = &key[..colon_pos];
-        if prefix != att {
-            eprintln!(
-                "Found wrong key w.r.t. smart graph attribute: {} (smart = {}) in line {}",
-                key, att, count
-            );
-            let suffix = &key[colon_pos + 1..];
-            parts[key_pos as usize] = quote_string(&(att + ":" + suffix), quo);
+/// Computes the geohash of a location with the given number of characters.
+fn geohash(lat: f64, lon: f64, precision: usize) -> String {
+    const BASE32: &[u8] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+    let (mut lat_range, mut lon_range) = ((-90.0, 90.0), (-180.0, 180.0));
+    let mut hash = String::with_capacity(precision);
+    let mut even = true;
+    let mut idx = 0;
+    for bit in 0..precision * 5 {
+        let (range, val) = if even {
+            (&mut lon_range, lon)
+        } else {
+            (&mut lat_range, lat)
+        };
+        let mid = (range.0 + range.1) / 2.0;
+        idx <<= 1;
+        if val >= mid {
+            idx |= 1;
+            range.0 = mid;
+        } else {
+            range.1 = mid;
+        }
+        even = !even;
+        if bit % 5 == 4 {
+            hash.push(BASE32[idx] as char);
+            idx = 0;
         }
     }
+    hash
+}
 
-    // Write out
-    if !parts.is_empty() {
-        write!(out, "{}", parts[0]).unwrap();
+/// Parses a template like `${country}-${lower(region)}`: text outside of
+/// `${...}` is taken literally. Without any `${`, the whole template is a
+/// single expression like `upper(substr(tenant, 0, 3))`.
+fn parse_template(s: &str) -> Result<Expr, String> {
+    template_expr(s).map_err(|e| format!("Invalid expression '{}': {}", s, e))
+}
+
+fn template_expr(s: &str) -> Result<Expr, String> {
+    if !s.contains("${") {
+        return parse_expr(s);
+    }
+    let mut parts = Vec::new();
+    let mut rest = s;
+    while let Some(start) = rest.find("${") {
+        if start > 0 {
+            parts.push(Expr::Literal(rest[..start].to_string()));
+        }
+        let mut parser = ExprParser {
+            chars: rest[start + 2..].chars().collect(),
+            pos: 0,
+        };
+        let e = parser.expr()?;
+        parser.expect('}')?;
+        parts.push(e);
+        let consumed: usize = parser.chars[..parser.pos]
+            .iter()
+            .map(|c| c.len_utf8())
+            .sum();
+        rest = &rest[start + 2 + consumed..];
     }
-    for i in 1..parts.len() {
-        write!(out, "{}{}", sep, parts[i]).unwrap();
+    if !rest.is_empty() {
+        parts.push(Expr::Literal(rest.to_string()));
     }
-    writeln!(out).unwrap();
+    Ok(Expr::Concat(parts))
 }
 
-// -----------------------------------------------------------------------------
-// JSONL transformations for vertices
-// -----------------------------------------------------------------------------
+/// Parses a single expression, see `parse_template`.
+fn parse_expr(s: &str) -> Result<Expr, String> {
+    let mut parser = ExprParser {
+        chars: s.chars().collect(),
+        pos: 0,
+    };
+    let e = parser.expr()?;
+    parser.skip_ws();
+    if parser.pos < parser.chars.len() {
+        return Err(format!(
+            "Unexpected '{}' at position {} in expression.",
+            parser.chars[parser.pos], parser.pos
+        ));
+    }
+    Ok(e)
+}
 
-/// Extract the string value from a JSON field or use a default. This is
-/// the simplified Rust version for the C++: `smartToString(...)`.
-fn smart_to_string(val: Option<&Value>, smart_default: &str, count: usize) -> String {
-    if let Some(v) = val {
-        match v {
-            Value::String(s) => {
-                return s.clone();
+/// A recursive descent parser for expressions. An expression is a string
+/// literal in single or double quotes, an integer, a function call or a
+/// column/attribute name, `col(<name>)` also takes a name like `1`.
+struct ExprParser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl ExprParser {
+    fn skip_ws(&mut self) {
+        while self.chars.get(self.pos).is_some_and(|c| c.is_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        self.skip_ws();
+        if self.chars.get(self.pos) != Some(&c) {
+            return Err(format!(
+                "Expected '{}' at position {} in expression.",
+                c, self.pos
+            ));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn expr(&mut self) -> Result<Expr, String> {
+        self.skip_ws();
+        match self.chars.get(self.pos) {
+            Some(&q) if q == '\'' || q == '"' => {
+                self.pos += 1;
+                let mut s = String::new();
+                loop {
+                    match self.chars.get(self.pos) {
+                        None => return Err("Unterminated string in expression.".to_string()),
+                        Some('\\') => {
+                            self.pos += 1;
+                            s.extend(self.chars.get(self.pos));
+                        }
+                        Some(&c) if c == q => break,
+                        Some(&c) => s.push(c),
+                    }
+                    self.pos += 1;
+                }
+                self.pos += 1;
+                Ok(Expr::Literal(s))
             }
-            Value::Null => {
-                if !smart_default.is_empty() {
+            Some(c) if c.is_ascii_digit() => Ok(Expr::Number(self.integer()?.to_string())),
+            _ => {
+                let name = self.name()?;
+                self.skip_ws();
+                if self.chars.get(self.pos) == Some(&'(') {
+                    self.pos += 1;
+                    self.call(&name)
+                } else {
+                    Ok(Expr::Field(name.replace('\\', ""), parse_path(&name)))
+                }
+            }
+        }
+    }
+
+    /// A name runs up to whitespace or one of `(),'"}`, a backslash escapes
+    /// the next character and is kept for `parse_path`.
+    fn name(&mut self) -> Result<String, String> {
+        let mut name = String::new();
+        while let Some(&c) = self.chars.get(self.pos) {
+            if c.is_whitespace() || "(),'\"}".contains(c) {
+                break;
+            }
+            name.push(c);
+            if c == '\\' {
+                self.pos += 1;
+                name.extend(self.chars.get(self.pos));
+            }
+            self.pos += 1;
+        }
+        if name.is_empty() {
+            return Err(format!(
+                "Expected a name at position {} in expression.",
+                self.pos
+            ));
+        }
+        Ok(name)
+    }
+
+    fn integer(&mut self) -> Result<usize, String> {
+        self.skip_ws();
+        let start = self.pos;
+        while self.chars.get(self.pos).is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        let digits: String = self.chars[start..self.pos].iter().collect();
+        digits
+            .parse()
+            .map_err(|_| format!("Expected a number at position {} in expression.", start))
+    }
+
+    /// Parses the arguments of a function call after the opening bracket.
+    fn call(&mut self, name: &str) -> Result<Expr, String> {
+        let e = match name {
+            "upper" => Expr::Upper(Box::new(self.expr()?)),
+            "lower" => Expr::Lower(Box::new(self.expr()?)),
+            "trim" => Expr::Trim(Box::new(self.expr()?)),
+            "col" => {
+                self.skip_ws();
+                let name = self.name()?;
+                Expr::Field(name.replace('\\', ""), parse_path(&name))
+            }
+            "substr" => {
+                let e = self.expr()?;
+                self.expect(',')?;
+                let start = self.integer()?;
+                let len = if self.comma() {
+                    Some(self.integer()?)
+                } else {
+                    None
+                };
+                Expr::Substr(Box::new(e), start, len)
+            }
+            "lpad" | "rpad" => {
+                let e = self.expr()?;
+                self.expect(',')?;
+                let width = self.integer()?;
+                let fill = if self.comma() {
+                    match self.expr()? {
+                        Expr::Literal(s) | Expr::Number(s) if s.chars().count() == 1 => {
+                            s.chars().next().unwrap()
+                        }
+                        _ => {
+                            return Err(format!(
+                                "The fill character of {} must be a single character.",
+                                name
+                            ))
+                        }
+                    }
+                } else {
+                    ' '
+                };
+                Expr::Pad(Box::new(e), width, fill, name == "lpad")
+            }
+            "concat" => {
+                let mut parts = vec![self.expr()?];
+                while self.comma() {
+                    parts.push(self.expr()?);
+                }
+                Expr::Concat(parts)
+            }
+            "geohash" => {
+                let mut args = vec![self.expr()?];
+                while self.comma() {
+                    args.push(self.expr()?);
+                }
+                let precision = match args.pop() {
+                    Some(Expr::Number(p)) if !args.is_empty() => p.parse().unwrap_or(0),
+                    _ => 0,
+                };
+                geohash_expr(args, precision)?
+            }
+            _ => return Err(format!("Unknown function {} in expression.", name)),
+        };
+        self.expect(')')?;
+        Ok(e)
+    }
+
+    /// Consumes a comma if there is one.
+    fn comma(&mut self) -> bool {
+        self.skip_ws();
+        if self.chars.get(self.pos) == Some(&',') {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Lookup table for smart graph attribute values
+// -----------------------------------------------------------------------------
+
+/// What to do with a value which is not found in the smart map.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum MapFallback {
+    Keep,
+    Error,
+    Default(String),
+}
+
+fn parse_map_fallback(s: &str) -> Option<MapFallback> {
+    match s {
+        "keep" => Some(MapFallback::Keep),
+        "error" => Some(MapFallback::Error),
+        _ => s
+            .strip_prefix("default:")
+            .filter(|v| !v.is_empty())
+            .map(|v| MapFallback::Default(v.to_string())),
+    }
+}
+
+/// Maps raw values to smart graph attribute values, loaded from the file
+/// given with `--smart-map`.
+#[derive(Debug)]
+struct SmartMap {
+    values: HashMap<String, String>,
+    fallback: MapFallback,
+}
+
+impl SmartMap {
+    /// Loads the table from a CSV file in the dialect of the input, the
+    /// first column is the raw value and the second one the smart value.
+    /// The first line is a header line, whether the data has one or not.
+    /// If a raw value occurs more than once, the first one wins.
+    fn load(file_name: &str, csv: &CsvDialect, fallback: MapFallback) -> Result<SmartMap, String> {
+        let file = File::open(file_name)
+            .map_err(|e| format!("Cannot open smart map file {}: {}", file_name, e))?;
+        let mut values = HashMap::new();
+        for (nr, line) in BufReader::new(file).lines().enumerate().skip(1) {
+            let line =
+                line.map_err(|e| format!("Cannot read smart map file {}: {}", file_name, e))?;
+            if line.is_empty() {
+                continue;
+            }
+            let parts = split(&line, csv);
+            if parts.len() < 2 {
+                return Err(format!(
+                    "Line {} of smart map file {} has less than two columns.",
+                    nr + 1,
+                    file_name
+                ));
+            }
+            let raw = unquote(&parts[0], csv);
+            let smart = unquote(&parts[1], csv);
+            match values.get(&raw) {
+                Some(old) if *old != smart => eprintln!(
+                    "Value {} is mapped to both {} and {} in smart map file {}, using {}.",
+                    raw, old, smart, file_name, old
+                ),
+                Some(_) => {}
+                None => {
+                    values.insert(raw, smart);
+                }
+            }
+        }
+        println!(
+            "{:.3} Loaded {} values from smart map file {}.",
+            elapsed(),
+            values.len(),
+            file_name
+        );
+        Ok(SmartMap { values, fallback })
+    }
+
+    /// Maps a raw value with the fallback applied, returns `None` if the
+    /// value is not found and the fallback is `MapFallback::Error`.
+    fn lookup(&self, raw: &str) -> Option<String> {
+        match (self.values.get(raw), &self.fallback) {
+            (Some(smart), _) => Some(smart.clone()),
+            (None, MapFallback::Keep) => Some(raw.to_string()),
+            (None, MapFallback::Default(smart)) => Some(smart.clone()),
+            (None, MapFallback::Error) => None,
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Bucketing of smart values
+// -----------------------------------------------------------------------------
+
+/// How a smart value is put into a bucket with `--smart-bucket`.
+#[derive(Debug, Clone)]
+enum Bucket {
+    // truncates a date to the given unit
+    Date(DateUnit, DateFormat),
+    // fixed-width numeric ranges given by width and offset, the value is
+    // the lower bound of the range with the given number of decimals
+    Width(f64, f64, usize),
+    // ranges between ascending breakpoints, the value is the number of the
+    // range, 0 is below the first breakpoint
+    Breaks(Vec<f64>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DateUnit {
+    Year,
+    Month,
+    Day,
+    Hour,
+}
+
+/// How dates are given in the data: with a pattern like `%Y-%m-%d` or as
+/// seconds or milliseconds since the epoch.
+#[derive(Debug, Clone)]
+enum DateFormat {
+    Pattern(String),
+    Epoch,
+    EpochMillis,
+}
+
+impl Bucket {
+    /// Puts a value into its bucket, returns `None` if the value is no
+    /// valid number or date.
+    fn apply(&self, val: &str) -> Option<String> {
+        match self {
+            Bucket::Date(unit, format) => {
+                let [y, m, d, h] = date_fields(val.trim(), format)?;
+                if m.is_some_and(|m| !(1..=12).contains(&m))
+                    || d.is_some_and(|d| !(1..=31).contains(&d))
+                    || h.is_some_and(|h| h > 23)
+                {
+                    return None;
+                }
+                Some(match unit {
+                    DateUnit::Year => format!("{:04}", y?),
+                    DateUnit::Month => format!("{:04}-{:02}", y?, m?),
+                    DateUnit::Day => format!("{:04}-{:02}-{:02}", y?, m?, d?),
+                    DateUnit::Hour => format!("{:04}-{:02}-{:02}T{:02}", y?, m?, d?, h?),
+                })
+            }
+            Bucket::Width(width, offset, decimals) => {
+                let x: f64 = val.trim().parse().ok().filter(|x: &f64| x.is_finite())?;
+                let lower = ((x - offset) / width).floor() * width + offset;
+                // rounding drops errors like 0.30000000000000004, adding
+                // 0.0 turns -0 into 0
+                let scale = 10f64.powi(*decimals as i32);
+                Some(format!(
+                    "{:.*}",
+                    decimals,
+                    (lower * scale).round() / scale + 0.0
+                ))
+            }
+            Bucket::Breaks(breaks) => {
+                let x: f64 = val.trim().parse().ok().filter(|x: &f64| !x.is_nan())?;
+                Some(breaks.partition_point(|b| *b <= x).to_string())
+            }
+        }
+    }
+}
+
+/// Extracts year, month, day and hour from a date, fields which are not
+/// in the format are `None`. Text after the end of the pattern is ignored,
+/// a date may also end early, like `2024-03-05` for `%Y-%m-%dT%H`.
+fn date_fields(s: &str, format: &DateFormat) -> Option<[Option<i64>; 4]> {
+    let pattern = match format {
+        DateFormat::Pattern(p) => p,
+        DateFormat::Epoch | DateFormat::EpochMillis => {
+            let mut secs: i64 = s.parse().ok()?;
+            if let DateFormat::EpochMillis = format {
+                secs = secs.div_euclid(1000);
+            }
+            let (y, m, d) = civil_from_days(secs.div_euclid(86400));
+            let h = secs.rem_euclid(86400) / 3600;
+            return Some([Some(y), Some(m), Some(d), Some(h)]);
+        }
+    };
+    let mut fields = [None; 4];
+    let mut input = s.chars().peekable();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        if input.peek().is_none() {
+            break;
+        }
+        if c != '%' {
+            if input.next()? != c {
+                return None;
+            }
+            continue;
+        }
+        let (field, max_digits) = match chars.next()? {
+            'Y' => (Some(0), 4),
+            'm' => (Some(1), 2),
+            'd' => (Some(2), 2),
+            'H' => (Some(3), 2),
+            'M' | 'S' => (None, 2),
+            _ => {
+                if input.next()? != '%' {
+                    return None;
+                }
+                continue;
+            }
+        };
+        let mut n = 0;
+        let mut digits = 0;
+        while digits < max_digits {
+            let Some(digit) = input.peek().and_then(|c| c.to_digit(10)) else {
+                break;
+            };
+            n = n * 10 + digit as i64;
+            input.next();
+            digits += 1;
+        }
+        if digits == 0 {
+            return None;
+        }
+        if let Some(i) = field {
+            fields[i] = Some(n);
+        }
+    }
+    Some(fields)
+}
+
+/// Converts days since 1970-01-01 into year, month and day of the
+/// proleptic Gregorian calendar.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    (y, m, d)
+}
+
+/// Parses a date format for `--date-format`: `epoch`, `epoch-ms` or a
+/// pattern with `%Y`, `%m`, `%d`, `%H`, `%M`, `%S` and `%%`.
+fn parse_date_format(s: &str) -> Result<DateFormat, String> {
+    match s {
+        "epoch" => return Ok(DateFormat::Epoch),
+        "epoch-ms" => return Ok(DateFormat::EpochMillis),
+        _ => {}
+    }
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '%' && !matches!(chars.next(), Some('Y' | 'm' | 'd' | 'H' | 'M' | 'S' | '%')) {
+            return Err(format!("Invalid date format '{}'.", s));
+        }
+    }
+    Ok(DateFormat::Pattern(s.to_string()))
+}
+
+/// Returns the number of decimal places of a number like `0.25` or
+/// `2.5e-1`.
+fn decimal_places(s: &str) -> usize {
+    let s = s.trim();
+    let (mantissa, exp) = match s.split_once(['e', 'E']) {
+        Some((m, e)) => (m, e.parse::<i64>().unwrap_or(0)),
+        None => (s, 0),
+    };
+    let fraction = mantissa.split_once('.').map_or(0, |(_, f)| f.len() as i64);
+    (fraction - exp).clamp(0, 17) as usize
+}
+
+/// Parses a bucket specification for `--smart-bucket`: `year`, `month`,
+/// `day`, `hour`, `width:<width>[:<offset>]` or `breaks:<b1>,<b2>,...`.
+fn parse_bucket(spec: &str, date_format: &str) -> Result<Bucket, String> {
+    let unit = match spec {
+        "year" => Some(DateUnit::Year),
+        "month" => Some(DateUnit::Month),
+        "day" => Some(DateUnit::Day),
+        "hour" => Some(DateUnit::Hour),
+        _ => None,
+    };
+    if let Some(unit) = unit {
+        return Ok(Bucket::Date(unit, parse_date_format(date_format)?));
+    }
+    let invalid = || format!("Invalid bucket '{}'.", spec);
+    let number = |s: &str| s.trim().parse::<f64>().ok().filter(|x| x.is_finite());
+    match spec.split_once(':') {
+        Some(("width", rest)) => {
+            let (width, offset) = match rest.split_once(':') {
+                Some((w, o)) => (w, o),
+                None => (rest, "0"),
+            };
+            let decimals = decimal_places(width).max(decimal_places(offset));
+            match (number(width), number(offset)) {
+                (Some(w), Some(o)) if w > 0.0 => Ok(Bucket::Width(w, o, decimals)),
+                _ => Err(invalid()),
+            }
+        }
+        Some(("breaks", rest)) => {
+            let breaks: Option<Vec<f64>> = rest.split(',').map(number).collect();
+            match breaks {
+                Some(b) if b.windows(2).all(|w| w[0] < w[1]) => Ok(Bucket::Breaks(b)),
+                _ => Err(invalid()),
+            }
+        }
+        _ => Err(invalid()),
+    }
+}
+
+// -----------------------------------------------------------------------------
+// CSV transformations for vertices (mimicking the C++ version)
+// -----------------------------------------------------------------------------
+
+/// Column positions in a CSV vertex file, as found in (or added to) the
+/// header. A position of -1 means that the column does not exist.
+struct VertexColumns {
+    ncols: usize,
+    smart_attr_pos: i32,
+    smart_value_pos: i32,
+    key_pos: i32,
+    key_value_pos: i32,
+    id_pos: i32,
+    // the expressions of the options, bound to the columns
+    smart_expr: Option<Expr>,
+    key_expr: Option<Expr>,
+}
+
+/// Extracts the smart graph attribute value from `val` with the smart
+/// regex: the first capture group or, if there is none, the whole match.
+fn smart_from_regex(re: &Regex, val: &str) -> Option<String> {
+    let caps = re.captures(val)?;
+    let m = caps.get(1).or_else(|| caps.get(0))?;
+    Some(m.as_str().to_string())
+}
+
+/// Computes the shard prefix of a vertex in an EnterpriseGraph from its key
+/// (without an existing prefix): the folded FNV-1a hash as 8 hex digits,
+/// such that the edges get the same prefix without looking up the vertex.
+fn enterprise_prefix(key: &str) -> String {
+    let key = key.find(':').map_or(key, |pos| &key[pos + 1..]);
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in key.bytes() {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    format!("{:08x}", (hash ^ (hash >> 32)) as u32)
+}
+
+/// Builds the new `_id` value for a vertex from the old one and the new key.
+/// Returns `None` if no collection name is known.
+fn rewrite_id(old_id: &str, new_key: &str, id_collection: &str) -> Option<String> {
+    let coll = if !id_collection.is_empty() {
+        id_collection
+    } else {
+        &old_id[..old_id.find('/')?]
+    };
+    Some(format!("{}/{}", coll, new_key))
+}
+
+/// Transforms a single CSV line for a vertex and returns the new `_key`.
+fn transform_vertex_csv(
+    line: &str,
+    count: u64,
+    opts: &VertexOptions,
+    cols: &VertexColumns,
+    out: &mut RecordWriter,
+) -> Result<Option<String>, i32> {
+    let csv = &opts.csv;
+    let smart_attr_pos = cols.smart_attr_pos;
+    let smart_value_pos = cols.smart_value_pos;
+    let key_pos = cols.key_pos;
+    let key_value_pos = cols.key_value_pos;
+    let smart_index = opts.smart_index;
+
+    let mut parts = split(line, csv);
+    // Extend with empty columns if needed
+    while parts.len() < cols.ncols {
+        parts.push(String::new());
+    }
+    // Also ensure if smart_attr_pos or key_pos are out-of-range, add empty
+    if smart_attr_pos >= 0 && smart_attr_pos as usize >= parts.len() {
+        parts.push(String::new());
+    }
+    if key_pos as usize >= parts.len() {
+        parts.push(String::new());
+    }
+
+    // Find the smart graph attribute value
+    let regex_att = || {
+        let re = opts.smart_regex.as_ref()?;
+        let source_pos = if smart_value_pos >= 0 {
+            smart_value_pos
+        } else if key_value_pos >= 0 {
+            key_value_pos
+        } else {
+            key_pos
+        };
+        let source = unquote(&parts[source_pos as usize], csv);
+        let att = smart_from_regex(re, &source);
+        if att.is_none() {
+            eprintln!(
+                "Smart regex does not match {} in line {}, keeping the smart graph attribute.",
+                source, count
+            );
+        }
+        att
+    };
+    // In an EnterpriseGraph, the prefix is computed from the key below
+    let att = if opts.enterprise {
+        String::new()
+    } else {
+        let att = if let Some(expr) = &cols.smart_expr {
+            let val = expr.eval(&Record::Csv(&parts, csv));
+            if val.is_empty() {
+                eprintln!("Smart expression is empty in line {}.", count);
+            }
+            parts[smart_attr_pos as usize] = quote_string(&val, csv);
+            val
+        } else if let Some(val) = regex_att() {
+            parts[smart_attr_pos as usize] = quote_string(&val, csv);
+            val
+        } else if opts.smart_regex.is_none()
+            && smart_value_pos >= 0
+            && (smart_value_pos as usize) < parts.len()
+        {
+            let mut val = unquote(&parts[smart_value_pos as usize], csv);
+            if smart_index > 0 && (val.len() as i32) > smart_index {
+                val = val[..smart_index as usize].to_string();
+            }
+            parts[smart_attr_pos as usize] = quote_string(&val, csv);
+            val
+        } else {
+            unquote(&parts[smart_attr_pos as usize], csv)
+        };
+        let att = match &opts.parent_lookup {
+            Some(parents) => {
+                let Some(parent_att) = parents.lookup(&att) else {
+                    eprintln!("Parent {} in line {} not found.", att, count);
+                    return Err(6);
+                };
+                parts[smart_attr_pos as usize] = quote_string(&parent_att, csv);
+                parent_att
+            }
+            None => att,
+        };
+        let att = match &opts.smart_bucket {
+            Some(bucket) => match bucket.apply(&att) {
+                Some(val) => {
+                    parts[smart_attr_pos as usize] = quote_string(&val, csv);
+                    val
+                }
+                None => {
+                    eprintln!(
+                        "Cannot put smart value {} in line {} into a bucket, keeping it.",
+                        att, count
+                    );
+                    att
+                }
+            },
+            None => att,
+        };
+        let att = match &opts.smart_map {
+            Some(map) => {
+                let Some(mapped) = map.lookup(&att) else {
+                    eprintln!(
+                        "Smart value {} in line {} not found in the smart map.",
+                        att, count
+                    );
+                    return Err(6);
+                };
+                parts[smart_attr_pos as usize] = quote_string(&mapped, csv);
+                mapped
+            }
+            None => att,
+        };
+        att
+    };
+
+    // Now handle the key
+    let key = if let Some(expr) = &cols.key_expr {
+        expr.eval(&Record::Csv(&parts, csv))
+    } else if key_value_pos >= 0 && (key_value_pos as usize) < parts.len() {
+        unquote(&parts[key_value_pos as usize], csv)
+    } else {
+        unquote(&parts[key_pos as usize], csv)
+    };
+    let att = if opts.enterprise {
+        enterprise_prefix(&key)
+    } else {
+        att
+    };
+
+    if let Some(colon_pos) = key.find(':') {
+        // already has a colon
+        let prefix = &key[..colon_pos];
+        if prefix != att {
+            eprintln!(
+                "Found wrong key w.r.t. smart graph attribute: {} (smart = {}) in line {}",
+                key, att, count
+            );
+            let suffix = &key[colon_pos + 1..];
+            parts[key_pos as usize] = quote_string(&(att + ":" + suffix), csv);
+        }
+    } else {
+        // not yet transformed
+        parts[key_pos as usize] = quote_string(&(att.clone() + ":" + &key), csv);
+    }
+
+    let new_key = unquote(&parts[key_pos as usize], csv);
+
+    // Adjust or remove _id
+    if cols.id_pos >= 0 {
+        let id_pos = cols.id_pos as usize;
+        if opts.drop_id {
+            parts.remove(id_pos);
+        } else {
+            let old_id = unquote(&parts[id_pos], csv);
+            match rewrite_id(&old_id, &new_key, &opts.id_collection) {
+                Some(new_id) => parts[id_pos] = quote_string(&new_id, csv),
+                None => eprintln!(
+                    "Cannot rewrite _id {} without collection name in line {}",
+                    old_id, count
+                ),
+            }
+        }
+    }
+
+    out.write_fields(parts);
+    Ok(Some(new_key))
+}
+
+// -----------------------------------------------------------------------------
+// JSONL transformations for vertices
+// -----------------------------------------------------------------------------
+
+/// Splits a dotted attribute path like `address.country` into attribute
+/// names. A backslash escapes the next character, such that `a\.b` is the
+/// single attribute `a.b`.
+fn parse_path(path: &str) -> Vec<String> {
+    let mut parts = vec![String::new()];
+    let mut chars = path.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => parts.last_mut().unwrap().push(chars.next().unwrap_or('\\')),
+            '.' => parts.push(String::new()),
+            _ => parts.last_mut().unwrap().push(c),
+        }
+    }
+    parts
+}
+
+/// Looks up the value at an attribute path in a JSON object.
+fn get_path<'a>(obj: &'a Map<String, Value>, path: &[String]) -> Option<&'a Value> {
+    let (first, rest) = path.split_first()?;
+    rest.iter()
+        .try_fold(obj.get(first)?, |v, name| v.as_object()?.get(name))
+}
+
+fn get_path_mut<'a>(obj: &'a mut Map<String, Value>, path: &[String]) -> Option<&'a mut Value> {
+    let (first, rest) = path.split_first()?;
+    rest.iter().try_fold(obj.get_mut(first)?, |v, name| {
+        v.as_object_mut()?.get_mut(name)
+    })
+}
+
+/// The attribute paths in a JSONL vertex file from which the smart graph
+/// attribute value and the key are taken. An empty path is not used.
+struct VertexPaths {
+    smart_value: Vec<String>,
+    key_value: Vec<String>,
+}
+
+impl VertexPaths {
+    fn new(opts: &VertexOptions) -> VertexPaths {
+        let path = |p: &str| {
+            if p.is_empty() {
+                Vec::new()
+            } else {
+                parse_path(p)
+            }
+        };
+        VertexPaths {
+            smart_value: path(&opts.smart_value),
+            key_value: path(&opts.key_value),
+        }
+    }
+}
+
+/// Extract the string value from a JSON field or use a default. This is
+/// the simplified Rust version for the C++: `smartToString(...)`.
+fn smart_to_string(val: Option<&Value>, smart_default: &str, count: usize) -> String {
+    if let Some(v) = val {
+        match v {
+            Value::String(s) => {
+                return s.clone();
+            }
+            Value::Null => {
+                if !smart_default.is_empty() {
                     return smart_default.to_string();
                 }
             }
@@ -309,63 +1744,152 @@ fn smart_to_string(val: Option<&Value>, smart_default: &str, count: usize) -> St
     "".to_string()
 }
 
-/// Transform a single JSON line for a vertex, adjusting `_key` and the
-/// specified "smart graph attribute".
+/// Transform a single JSON line for a vertex, adjusting `_key`, `_id` and
+/// the specified "smart graph attribute". Returns the new `_key` if any.
 fn transform_vertex_jsonl(
     line: &str,
     count: usize,
-    smart_attr: &str,
-    smart_value: &str,
-    smart_index: i32,
-    smart_default: &str,
-    write_key: bool,
-    key_value: &str,
-    out: &mut dyn Write,
-) {
+    opts: &VertexOptions,
+    paths: &VertexPaths,
+    out: &mut RecordWriter,
+) -> Result<Option<String>, i32> {
+    let smart_attr = opts.smart_attr.as_str();
+    let smart_index = opts.smart_index;
+    let smart_default = opts.smart_default.as_str();
+
     // Parse JSON
     let parsed: Value = match serde_json::from_str(line) {
         Ok(v) => v,
         Err(e) => {
             eprintln!("JSON parse error on line {}: {}", count, e);
-            return;
+            return Ok(None);
         }
     };
 
     // We expect an object for each line
-    let obj = match parsed {
+    let mut obj = match parsed {
         Value::Object(m) => m,
         _ => {
             eprintln!(
                 "Expected an object in JSON line {}, found something else. Skipping.",
                 count
             );
-            return;
+            return Ok(None);
         }
     };
 
     // Derive the smart graph attribute
-    let att_val = if !smart_value.is_empty() {
-        smart_to_string(obj.get(smart_value), smart_default, count)
-    } else {
-        smart_to_string(obj.get(smart_attr), smart_default, count)
+    let regex_att = || {
+        let re = opts.smart_regex.as_ref()?;
+        let source = if !paths.smart_value.is_empty() {
+            get_path(&obj, &paths.smart_value)
+        } else if !paths.key_value.is_empty() {
+            get_path(&obj, &paths.key_value)
+        } else {
+            obj.get("_key")
+        };
+        let source = smart_to_string(source, "", count);
+        let att = smart_from_regex(re, &source);
+        if att.is_none() {
+            eprintln!(
+                "Smart regex does not match {} on line {}, keeping the smart graph attribute.",
+                source, count
+            );
+        }
+        att
     };
-    let mut final_att_val = att_val.clone();
-    if smart_index > 0 && (final_att_val.len() as i32) > smart_index {
-        final_att_val = final_att_val[..smart_index as usize].to_string();
-    }
-
-    // Figure out the new _key
-    let key_slice = if !key_value.is_empty() {
-        obj.get(key_value)
+    // In an EnterpriseGraph, the prefix is computed from the key below
+    let final_att_val = if opts.enterprise {
+        String::new()
+    } else {
+        let final_att_val = if let Some(expr) = &opts.smart_expr {
+            let val = expr.eval(&Record::Json(&obj));
+            if val.is_empty() {
+                eprintln!("Smart expression is empty on line {}.", count);
+            }
+            val
+        } else if let Some(val) = regex_att() {
+            val
+        } else {
+            let source = if !paths.smart_value.is_empty() && opts.smart_regex.is_none() {
+                get_path(&obj, &paths.smart_value)
+            } else {
+                obj.get(smart_attr)
+            };
+            let att_val = match source {
+                // numbers are expected here
+                Some(Value::Number(num))
+                    if opts.smart_bucket.is_some() || opts.parent_lookup.is_some() =>
+                {
+                    num.to_string()
+                }
+                _ => smart_to_string(source, smart_default, count),
+            };
+            if smart_index > 0 && (att_val.len() as i32) > smart_index {
+                att_val[..smart_index as usize].to_string()
+            } else {
+                att_val
+            }
+        };
+
+        let final_att_val = match &opts.parent_lookup {
+            Some(parents) => {
+                let Some(parent_att) = parents.lookup(&final_att_val) else {
+                    eprintln!("Parent {} on line {} not found.", final_att_val, count);
+                    return Err(6);
+                };
+                parent_att
+            }
+            None => final_att_val,
+        };
+        let final_att_val = match &opts.smart_bucket {
+            Some(bucket) => bucket.apply(&final_att_val).unwrap_or_else(|| {
+                eprintln!(
+                    "Cannot put smart value {} on line {} into a bucket, keeping it.",
+                    final_att_val, count
+                );
+                final_att_val
+            }),
+            None => final_att_val,
+        };
+        let final_att_val = match &opts.smart_map {
+            Some(map) => {
+                let Some(mapped) = map.lookup(&final_att_val) else {
+                    eprintln!(
+                        "Smart value {} on line {} not found in the smart map.",
+                        final_att_val, count
+                    );
+                    return Err(6);
+                };
+                mapped
+            }
+            None => final_att_val,
+        };
+        final_att_val
+    };
+
+    // Figure out the new _key
+    let key_expr_val = opts
+        .key_expr
+        .as_ref()
+        .map(|expr| Value::String(expr.eval(&Record::Json(&obj))));
+    let key_slice = if key_expr_val.is_some() {
+        key_expr_val.as_ref()
+    } else if !paths.key_value.is_empty() {
+        get_path(&obj, &paths.key_value)
     } else {
         obj.get("_key")
     };
+    let final_att_val = match key_slice {
+        Some(Value::String(key_str)) if opts.enterprise => enterprise_prefix(key_str),
+        _ => final_att_val,
+    };
     let mut new_key = String::new();
     if let Some(Value::String(key_str)) = key_slice {
         let split_pos = key_str.find(':');
         if let Some(pos) = split_pos {
             // Already has a colon
-            if &key_str[..pos] != final_att_val {
+            if key_str[..pos] != final_att_val {
                 eprintln!(
                     "_key is already smart, but with the wrong prefix on line {}: {} (smart = {})",
                     count, key_str, final_att_val
@@ -382,33 +1906,1536 @@ fn transform_vertex_jsonl(
         }
     }
 
-    // Write out a new object
-    // The C++ code prints `_key` and the smart attribute first, then the rest.
-    let mut new_obj = Map::new();
+    // Rewrite `_id`, `_key` and the smart graph attribute in place, such
+    // that all other attributes keep their order and their exact values.
+    // A new `_key` is put first and a new smart graph attribute after it.
+    if opts.drop_id {
+        obj.shift_remove("_id");
+    } else if let Some(Value::String(old_id)) = obj.get("_id") {
+        if new_key.is_empty() {
+            eprintln!(
+                "Cannot rewrite _id {} without _key on line {}, keeping it.",
+                old_id, count
+            );
+        } else {
+            match rewrite_id(old_id, &new_key, &opts.id_collection) {
+                Some(new_id) => {
+                    obj.insert("_id".to_string(), Value::String(new_id));
+                }
+                None => {
+                    eprintln!(
+                        "Cannot rewrite _id {} without collection name on line {}",
+                        old_id, count
+                    );
+                }
+            }
+        }
+    }
+    if opts.write_key || !new_key.is_empty() {
+        if obj.contains_key("_key") {
+            obj.insert("_key".to_string(), Value::String(new_key.clone()));
+        } else {
+            obj.shift_insert(0, "_key".to_string(), Value::String(new_key.clone()));
+        }
+    }
+    if opts.enterprise {
+        // EnterpriseGraphs have no smart graph attribute
+    } else if obj.contains_key(smart_attr) {
+        obj.insert(smart_attr.to_string(), Value::String(final_att_val));
+    } else {
+        let pos = obj.keys().position(|k| k == "_key").map_or(0, |p| p + 1);
+        obj.shift_insert(pos, smart_attr.to_string(), Value::String(final_att_val));
+    }
+
+    out.write_object(obj);
+    Ok(Some(new_key).filter(|k| !k.is_empty()))
+}
+
+// -----------------------------------------------------------------------------
+// Conversion between CSV and JSONL on output
+// -----------------------------------------------------------------------------
+
+/// Number of records used to infer the column types of CSV input or to
+/// discover the columns of JSONL input, if the output type differs.
+const SAMPLE_SIZE: usize = 1000;
+
+/// Settings for writing the output in a different format than the input.
+#[derive(Debug, Clone, Default)]
+struct OutputFormat {
+    // the output data type, if not set, it is the same as the input type
+    data_type: Option<DataType>,
+    // if true, CSV values are written as JSON numbers, booleans or null,
+    // if all values of their column in the sample allow this
+    infer_types: bool,
+    // the CSV columns for JSONL input, discovered from a sample if empty
+    columns: Vec<String>,
+}
+
+/// Writes the transformed records. CSV records (as raw fields) and JSON
+/// objects are written as they are, unless the output type differs from
+/// the input type, in which case they are converted. For this, the first
+/// records may be kept back as a sample until the column types or the
+/// columns are known.
+struct RecordWriter {
+    out: BufWriter<File>,
+    input_type: DataType,
+    output_type: DataType,
+    csv: CsvDialect,
+    infer_types: bool,
+    // columns which are never inferred, like `_key` or `_from`
+    reserved: Vec<String>,
+    // the CSV column names, either of the input or of the output
+    columns: Vec<String>,
+    // for CSV input, which columns are written as inferred JSON values
+    typed: Vec<bool>,
+    sampling: bool,
+    sample_fields: Vec<Vec<String>>,
+    sample_objects: Vec<Map<String, Value>>,
+    // attributes which are not among the CSV output columns
+    dropped: BTreeSet<String>,
+}
+
+impl RecordWriter {
+    fn new(
+        out: BufWriter<File>,
+        input_type: DataType,
+        csv: &CsvDialect,
+        format: &OutputFormat,
+        reserved: &[&str],
+    ) -> RecordWriter {
+        let output_type = format.data_type.unwrap_or(input_type);
+        let converting = output_type != input_type;
+        let mut writer = RecordWriter {
+            out,
+            input_type,
+            output_type,
+            csv: csv.clone(),
+            infer_types: format.infer_types,
+            reserved: reserved.iter().map(|r| r.to_string()).collect(),
+            columns: Vec::new(),
+            typed: Vec::new(),
+            sampling: converting
+                && match input_type {
+                    DataType::Csv => format.infer_types,
+                    DataType::Jsonl => format.columns.is_empty(),
+                },
+            sample_fields: Vec::new(),
+            sample_objects: Vec::new(),
+            dropped: BTreeSet::new(),
+        };
+        if converting && input_type == DataType::Jsonl && !format.columns.is_empty() {
+            writer.write_csv_header(&format.columns);
+        }
+        writer
+    }
+
+    fn write_csv_header(&mut self, columns: &[String]) {
+        let header_line = columns
+            .iter()
+            .map(|c| quote_string(c, &self.csv))
+            .collect::<Vec<String>>()
+            .join(&self.csv.sep);
+        writeln!(self.out, "{}", header_line).unwrap();
+        self.columns = columns.to_vec();
+    }
+
+    /// Sets the column names of CSV input and writes the header, if the
+    /// output is CSV as well.
+    fn write_header(&mut self, columns: &[String]) {
+        if self.output_type == DataType::Csv {
+            self.write_csv_header(columns);
+        } else {
+            self.columns = columns.to_vec();
+            self.typed = vec![false; columns.len()];
+        }
+    }
+
+    /// Writes a record of CSV input, the fields are still quoted.
+    fn write_fields(&mut self, parts: Vec<String>) {
+        if self.output_type == DataType::Csv {
+            writeln!(self.out, "{}", parts.join(&self.csv.sep)).unwrap();
+        } else if self.sampling {
+            self.sample_fields.push(parts);
+            if self.sample_fields.len() >= SAMPLE_SIZE {
+                self.end_sample();
+            }
+        } else {
+            self.write_fields_as_json(&parts);
+        }
+    }
+
+    /// Tells if the records are converted to another data type.
+    fn converts(&self) -> bool {
+        self.input_type != self.output_type
+    }
+
+    /// Writes a line of JSONL input, which is not converted.
+    fn write_line(&mut self, line: &str) {
+        writeln!(self.out, "{}", line).unwrap();
+    }
+
+    /// Writes a record of JSONL input.
+    fn write_object(&mut self, obj: Map<String, Value>) {
+        if self.output_type == DataType::Jsonl {
+            writeln!(self.out, "{}", Value::Object(obj)).unwrap();
+        } else if self.sampling {
+            self.sample_objects.push(obj);
+            if self.sample_objects.len() >= SAMPLE_SIZE {
+                self.end_sample();
+            }
+        } else {
+            self.write_object_as_csv(&obj);
+        }
+    }
+
+    fn write_fields_as_json(&mut self, parts: &[String]) {
+        let mut obj = Map::new();
+        for (i, name) in self.columns.iter().enumerate() {
+            let value = parts
+                .get(i)
+                .map(|p| unquote(p, &self.csv))
+                .unwrap_or_default();
+            let json = if !self.typed[i] {
+                Value::String(value)
+            } else if value.is_empty() {
+                Value::Null
+            } else {
+                infer_scalar(&value).unwrap_or(Value::String(value))
+            };
+            obj.insert(name.clone(), json);
+        }
+        writeln!(self.out, "{}", Value::Object(obj)).unwrap();
+    }
+
+    fn write_object_as_csv(&mut self, obj: &Map<String, Value>) {
+        let fields = self
+            .columns
+            .iter()
+            .map(|c| json_to_field(obj.get(c), &self.csv))
+            .collect::<Vec<String>>();
+        writeln!(self.out, "{}", fields.join(&self.csv.sep)).unwrap();
+        for k in obj.keys() {
+            if !self.columns.contains(k) {
+                self.dropped.insert(k.clone());
+            }
+        }
+    }
+
+    /// Decides about the column types or the columns from the sample and
+    /// writes the records kept back so far.
+    fn end_sample(&mut self) {
+        self.sampling = false;
+        match self.input_type {
+            DataType::Csv => {
+                let csv = &self.csv;
+                for (i, name) in self.columns.iter().enumerate() {
+                    let mut values = self
+                        .sample_fields
+                        .iter()
+                        .filter_map(|parts| parts.get(i))
+                        .map(|p| unquote(p, csv))
+                        .filter(|v| !v.is_empty())
+                        .peekable();
+                    self.typed[i] = self.infer_types
+                        && !self.reserved.contains(name)
+                        && values.peek().is_some()
+                        && values.all(|v| infer_scalar(&v).is_some());
+                }
+                for parts in std::mem::take(&mut self.sample_fields) {
+                    self.write_fields_as_json(&parts);
+                }
+            }
+            DataType::Jsonl => {
+                let mut columns: Vec<String> = Vec::new();
+                for obj in &self.sample_objects {
+                    for k in obj.keys() {
+                        if !columns.contains(k) {
+                            columns.push(k.clone());
+                        }
+                    }
+                }
+                if !columns.is_empty() {
+                    self.write_csv_header(&columns);
+                }
+                for obj in std::mem::take(&mut self.sample_objects) {
+                    self.write_object_as_csv(&obj);
+                }
+            }
+        }
+    }
+
+    /// Writes the rest of the sample and flushes the output.
+    fn finish(&mut self) -> std::io::Result<()> {
+        if self.sampling {
+            self.end_sample();
+        }
+        if !self.dropped.is_empty() {
+            eprintln!(
+                "Warning: attributes not among the CSV columns were dropped: {}",
+                self.dropped
+                    .iter()
+                    .cloned()
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            );
+        }
+        self.out.flush()
+    }
+}
+
+/// Returns the JSON number, boolean or null for a CSV value, if it is one.
+fn infer_scalar(s: &str) -> Option<Value> {
+    if s.trim() != s {
+        return None;
+    }
+    match serde_json::from_str::<Value>(s) {
+        Ok(v @ (Value::Number(_) | Value::Bool(_) | Value::Null)) => Some(v),
+        _ => None,
+    }
+}
+
+/// Returns the CSV field for a JSON value, missing values and null become
+/// empty fields, arrays and objects are written as JSON.
+fn json_to_field(val: Option<&Value>, csv: &CsvDialect) -> String {
+    match val {
+        None | Some(Value::Null) => String::new(),
+        Some(Value::String(s)) => quote_string(s, csv),
+        Some(v @ (Value::Number(_) | Value::Bool(_))) => v.to_string(),
+        Some(v) => quote_string(&v.to_string(), csv),
+    }
+}
+
+/// Returns the name of a converted file: the extension of `file_name` is
+/// replaced by the one of the data type.
+fn converted_file_name(file_name: &str, data_type: DataType) -> String {
+    let ext = match data_type {
+        DataType::Csv => "csv",
+        DataType::Jsonl => "jsonl",
+    };
+    Path::new(file_name)
+        .with_extension(ext)
+        .to_string_lossy()
+        .to_string()
+}
+
+// -----------------------------------------------------------------------------
+// Command line arguments shared between subcommands
+// -----------------------------------------------------------------------------
+
+/// Arguments for the input data format.
+fn format_args() -> Vec<Arg> {
+    vec![
+        Arg::new("type")
+            .long("type")
+            .num_args(1)
+            .default_value("csv")
+            .help("Input data type: csv or jsonl"),
+        Arg::new("separator")
+            .long("separator")
+            .default_value(",")
+            .help("Column separator for CSV"),
+        Arg::new("quote-char")
+            .long("quote-char")
+            .default_value("\"")
+            .help("Quote character for CSV"),
+        Arg::new("escape")
+            .long("escape")
+            .default_value("double")
+            .help("How quote characters are escaped in CSV: double, backslash or none"),
+        Arg::new("no-header")
+            .long("no-header")
+            .action(ArgAction::SetTrue)
+            .help("If present, CSV files have no header line"),
+        Arg::new("output-type")
+            .long("output-type")
+            .num_args(1)
+            .help("Output data type: csv or jsonl, default is the input data type"),
+        Arg::new("infer-types")
+            .long("infer-types")
+            .action(ArgAction::SetTrue)
+            .help("If present, CSV values are written as JSON numbers, booleans or null where possible"),
+        Arg::new("output-columns")
+            .long("output-columns")
+            .num_args(1)
+            .help("Comma separated CSV columns for JSONL input, default is to discover them"),
+    ]
+}
+
+/// Arguments which control how vertices are transformed.
+fn vertex_args() -> Vec<Arg> {
+    vec![
+        Arg::new("smart-graph-attribute")
+            .long("smart-graph-attribute")
+            .num_args(1)
+            .default_value("smart_id")
+            .help("Name of the smart graph attribute"),
+        Arg::new("write-key")
+            .long("write-key")
+            .action(ArgAction::SetTrue)
+            .help("If present, the `_key` attribute will be re-written"),
+        Arg::new("smart-value")
+            .long("smart-value")
+            .num_args(1)
+            .help("Attribute/column used to build the smart graph attribute"),
+        Arg::new("smart-index")
+            .long("smart-index")
+            .num_args(1)
+            .help("If given, only this many characters are taken from the smart value"),
+        Arg::new("smart-regex")
+            .long("smart-regex")
+            .num_args(1)
+            .conflicts_with("smart-index")
+            .help("Regular expression, its first capture group in the smart value (or the key) is the smart graph attribute value"),
+        Arg::new("smart-expr")
+            .long("smart-expr")
+            .num_args(1)
+            .conflicts_with_all(["smart-value", "smart-index", "smart-regex"])
+            .help("Template expression for the smart graph attribute value, like '${country}-${region}'"),
+        Arg::new("key-expr")
+            .long("key-expr")
+            .num_args(1)
+            .conflicts_with("key-value")
+            .help("Template expression for the `_key` suffix, like '${lpad(id, 8, \"0\")}'"),
+        Arg::new("smart-geohash")
+            .long("smart-geohash")
+            .num_args(1)
+            .requires("geo-attributes")
+            .conflicts_with_all(["smart-value", "smart-index", "smart-regex", "smart-expr"])
+            .help("Use the geohash with this many characters (1 to 12) of the location in --geo-attributes as smart value"),
+        Arg::new("geo-attributes")
+            .long("geo-attributes")
+            .num_args(1)
+            .requires("smart-geohash")
+            .help("Column/attribute with a GeoJSON point, or comma separated latitude and longitude columns/attributes"),
+        Arg::new("smart-bucket")
+            .long("smart-bucket")
+            .num_args(1)
+            .help("Put the smart value into a bucket: year, month, day, hour, width:<width>[:<offset>] or breaks:<b1>,<b2>,..."),
+        Arg::new("date-format")
+            .long("date-format")
+            .num_args(1)
+            .default_value("%Y-%m-%d")
+            .help("Format of dates for --smart-bucket: a pattern with %Y, %m, %d, %H, %M, %S, or epoch or epoch-ms"),
+        smart_map_arg(),
+        smart_map_fallback_arg(),
+        Arg::new("enterprise")
+            .long("enterprise")
+            .action(ArgAction::SetTrue)
+            .conflicts_with_all([
+                "smart-value",
+                "smart-index",
+                "smart-regex",
+                "smart-expr",
+                "smart-geohash",
+                "smart-bucket",
+                "smart-map",
+            ])
+            .help("EnterpriseGraph, the key prefix is a hash of the key and no smart graph attribute is written"),
+        Arg::new("smart-default")
+            .long("smart-default")
+            .num_args(1)
+            .help("Default value for smart graph attribute if not present (JSONL only)"),
+        Arg::new("key-value")
+            .long("key-value")
+            .num_args(1)
+            .help("Column/attribute name from which to get the value for `_key` suffix"),
+        Arg::new("id-collection")
+            .long("id-collection")
+            .num_args(1)
+            .help(
+                "Collection name for rewriting `_id`, default is the collection in the old `_id`, not in graph mode",
+            ),
+        Arg::new("drop-id")
+            .long("drop-id")
+            .action(ArgAction::SetTrue)
+            .conflicts_with("id-collection")
+            .help("If present, the `_id` attribute is removed instead of rewritten"),
+        Arg::new("rename-column")
+            .long("rename-column")
+            .num_args(1)
+            .action(ArgAction::Append)
+            .help("Rename a CSV column before processing: <colIndex>:<newName>, can be repeated"),
+        Arg::new("columns")
+            .long("columns")
+            .num_args(1)
+            .help("Comma separated CSV column names, used instead of the header line"),
+    ]
+}
+
+/// The lookup table for smart values, for vertex and edge mode.
+fn smart_map_arg() -> Arg {
+    Arg::new("smart-map")
+        .long("smart-map")
+        .num_args(1)
+        .help("CSV file with header line which maps raw values (first column) to smart graph attribute values (second column)")
+}
+
+fn smart_map_fallback_arg() -> Arg {
+    Arg::new("smart-map-fallback")
+        .long("smart-map-fallback")
+        .num_args(1)
+        .default_value("keep")
+        .help("What to do with values not found in the smart map: keep, error or default:<value>")
+}
+
+/// The edge specifications for the `edges` and `graph` subcommands.
+fn edges_arg() -> Arg {
+    Arg::new("edges")
+        .long("edges")
+        .num_args(..)
+        .required(true)
+        .help("One or more edge specifications: <edgefile>:<fromColls>:<toColls>[:<colIndex>:<newName> ...], collection lists are comma separated")
+}
+
+/// Arguments which control how edges are transformed.
+fn edge_args() -> Vec<Arg> {
+    vec![
+        Arg::new("on-conflict")
+            .long("on-conflict")
+            .num_args(1)
+            .value_parser(["first", "last", "error"])
+            .default_value("first")
+            .help("What to do if a vertex key occurs with different smart values: first, last or error"),
+        Arg::new("on-unresolved")
+            .long("on-unresolved")
+            .num_args(1)
+            .default_value("keep")
+            .help("What to do with edges whose _from or _to is not found: keep, drop-edge, reject, error or default:<value>"),
+        Arg::new("disjoint")
+            .long("disjoint")
+            .num_args(0..=1)
+            .default_missing_value("reject")
+            .value_parser(["reject", "error"])
+            .help("Disjoint smart graph, edges between different smart values are written to <edgefile>.crosspartition (reject) or abort (error)"),
+        Arg::new("map-collection")
+            .long("map-collection")
+            .num_args(1)
+            .action(ArgAction::Append)
+            .help("Rename a vertex collection in _from and _to: <old>=<new>, can be repeated"),
+        Arg::new("vertex-ref")
+            .long("vertex-ref")
+            .num_args(1)
+            .action(ArgAction::Append)
+            .help("Rewrite a vertex attribute with keys of other vertices: <coll>:<attr>[:<targetColl>], can be repeated; in edge mode the --vertices files are rewritten in place"),
+        Arg::new("from-attribute")
+            .long("from-attribute")
+            .num_args(1)
+            .default_value("_from")
+            .help("Attribute/column which contains the from vertex"),
+        Arg::new("to-attribute")
+            .long("to-attribute")
+            .num_args(1)
+            .default_value("_to")
+            .help("Attribute/column which contains the to vertex"),
+    ]
+}
+
+/// Returns the value of an optional string argument or an empty string.
+fn get_string(sub_m: &ArgMatches, name: &str) -> String {
+    sub_m
+        .get_one::<String>(name)
+        .unwrap_or(&"".to_string())
+        .clone()
+}
+
+/// Reads the smart regex, exits if it is invalid.
+fn get_smart_regex(sub_m: &ArgMatches) -> Option<Regex> {
+    let s = sub_m.get_one::<String>("smart-regex")?;
+    match parse_regex(s) {
+        Ok(re) => Some(re),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(-1);
+        }
+    }
+}
+
+/// Reads a template expression, exits if it is invalid.
+fn get_expr(sub_m: &ArgMatches, name: &str) -> Option<Expr> {
+    let s = sub_m.get_one::<String>(name)?;
+    match parse_template(s) {
+        Ok(expr) => Some(expr),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(-1);
+        }
+    }
+}
+
+/// Loads the smart map with the CSV dialect of the input, the separator is
+/// detected from the file extension if it is not given. Exits on errors.
+fn get_smart_map(sub_m: &ArgMatches, csv: &CsvDialect) -> Option<Arc<SmartMap>> {
+    let file_name = sub_m.get_one::<String>("smart-map")?;
+    let fallback_str = sub_m.get_one::<String>("smart-map-fallback").unwrap();
+    let Some(fallback) = parse_map_fallback(fallback_str) else {
+        eprintln!("Invalid value '{}' for --smart-map-fallback.", fallback_str);
+        std::process::exit(-1);
+    };
+    let mut csv = csv.clone();
+    override_with(
+        &mut csv.sep,
+        detect_format(file_name).1,
+        from_command_line(sub_m, "separator"),
+    );
+    match SmartMap::load(file_name, &csv, fallback) {
+        Ok(map) => Some(Arc::new(map)),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(-1);
+        }
+    }
+}
+
+/// Builds the geohash expression for `--smart-geohash` from the names of
+/// the location attributes.
+fn geohash_attributes(precision: usize, names: &[String]) -> Result<Expr, String> {
+    let args = names
+        .iter()
+        .map(|name| Expr::Field(name.clone(), parse_path(name)))
+        .collect();
+    geohash_expr(args, precision)
+}
 
-    if write_key || !new_key.is_empty() {
-        new_obj.insert("_key".to_string(), Value::String(new_key));
+/// Reads the geohash options, exits if they are invalid.
+fn get_smart_geohash(sub_m: &ArgMatches) -> Option<Expr> {
+    let precision_str = sub_m.get_one::<String>("smart-geohash")?;
+    let names_str = sub_m.get_one::<String>("geo-attributes").unwrap();
+    let Some(names) = parse_column_list(names_str) else {
+        eprintln!("Invalid value '{}' for --geo-attributes.", names_str);
+        std::process::exit(-1);
+    };
+    match geohash_attributes(precision_str.parse().unwrap_or(0), &names) {
+        Ok(expr) => Some(expr),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(-1);
+        }
     }
-    new_obj.insert(smart_attr.to_string(), Value::String(final_att_val));
+}
 
-    // Then copy over all other fields that are not `_key` / `smart_attr`
-    let reserved = vec!["_key".to_string(), smart_attr.to_string()];
-    for (k, v) in obj.into_iter() {
-        if !reserved.contains(&k) {
-            new_obj.insert(k, v);
+/// Reads the smart bucket with its date format, exits if it is invalid.
+fn get_smart_bucket(sub_m: &ArgMatches) -> Option<Bucket> {
+    let spec = sub_m.get_one::<String>("smart-bucket")?;
+    let date_format = sub_m.get_one::<String>("date-format").unwrap();
+    match parse_bucket(spec, date_format) {
+        Ok(bucket) => Some(bucket),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(-1);
         }
     }
+}
 
-    // Serialize to JSON
-    let output = Value::Object(new_obj);
-    if let Ok(line_out) = serde_json::to_string(&output) {
-        writeln!(out, "{}", line_out).unwrap();
+fn get_smart_index(sub_m: &ArgMatches) -> i32 {
+    let smart_index_str = get_string(sub_m, "smart-index");
+    if smart_index_str.is_empty() {
+        -1
     } else {
-        eprintln!(
-            "Failed to serialize transformed JSON for line {}. Skipping.",
-            count
+        smart_index_str.parse().unwrap_or(-1)
+    }
+}
+
+/// Reads the data type and the CSV dialect, exits on invalid values.
+fn get_format(sub_m: &ArgMatches) -> (DataType, CsvDialect) {
+    let data_type = parse_data_type(sub_m.get_one::<String>("type").unwrap());
+    let sep = parse_separator(sub_m.get_one::<String>("separator").unwrap());
+    let quo = parse_char(
+        sub_m.get_one::<String>("quote-char").unwrap(),
+        "quote character",
+    );
+    let esc = parse_escape_style(sub_m.get_one::<String>("escape").unwrap());
+    match (data_type, sep, quo, esc) {
+        (Ok(data_type), Ok(sep), Ok(quo), Ok(esc)) => (data_type, CsvDialect { sep, quo, esc }),
+        (Err(e), ..) | (_, Err(e), ..) | (_, _, Err(e), _) | (.., Err(e)) => {
+            eprintln!("{}", e);
+            std::process::exit(-1);
+        }
+    }
+}
+
+/// Reads the settings for converting the output, exits on invalid values.
+fn get_output_format(sub_m: &ArgMatches) -> OutputFormat {
+    let data_type = match sub_m.get_one::<String>("output-type") {
+        Some(s) => match parse_data_type(s) {
+            Ok(t) => Some(t),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(-1);
+            }
+        },
+        None => None,
+    };
+    let columns_str = get_string(sub_m, "output-columns");
+    let columns = if columns_str.is_empty() {
+        Vec::new()
+    } else {
+        match parse_column_list(&columns_str) {
+            Some(columns) => columns,
+            None => {
+                eprintln!("Invalid value '{}' for --output-columns.", columns_str);
+                std::process::exit(-1);
+            }
+        }
+    };
+    OutputFormat {
+        data_type,
+        infer_types: sub_m.get_flag("infer-types"),
+        columns,
+    }
+}
+
+/// Reads the `--rename-column` values, exits if one is invalid.
+fn get_column_renames(sub_m: &ArgMatches) -> Vec<(usize, String)> {
+    let mut column_renames = Vec::new();
+    for r in sub_m
+        .get_many::<String>("rename-column")
+        .unwrap_or_default()
+    {
+        match parse_rename(r) {
+            Some(rename) => column_renames.push(rename),
+            None => {
+                eprintln!("Invalid value '{}' for --rename-column.", r);
+                std::process::exit(-1);
+            }
+        }
+    }
+    column_renames
+}
+
+/// Reads the `--columns` value, exits if it is invalid.
+fn get_columns(sub_m: &ArgMatches) -> Vec<String> {
+    let columns_str = get_string(sub_m, "columns");
+    if columns_str.is_empty() {
+        return Vec::new();
+    }
+    match parse_column_list(&columns_str) {
+        Some(columns) => columns,
+        None => {
+            eprintln!("Invalid value '{}' for --columns.", columns_str);
+            std::process::exit(-1);
+        }
+    }
+}
+
+fn get_vertex_options(sub_m: &ArgMatches) -> VertexOptions {
+    let (data_type, csv) = get_format(sub_m);
+    let smart_map = get_smart_map(sub_m, &csv);
+    VertexOptions {
+        smart_attr: sub_m
+            .get_one::<String>("smart-graph-attribute")
+            .unwrap()
+            .clone(),
+        data_type,
+        csv,
+        write_key: sub_m.get_flag("write-key"),
+        smart_value: get_string(sub_m, "smart-value"),
+        smart_index: get_smart_index(sub_m),
+        smart_regex: get_smart_regex(sub_m),
+        smart_expr: get_expr(sub_m, "smart-expr").or_else(|| get_smart_geohash(sub_m)),
+        key_expr: get_expr(sub_m, "key-expr"),
+        smart_bucket: get_smart_bucket(sub_m),
+        smart_map,
+        // only set for the `smartjoin` subcommand, see `do_smartjoin`
+        parent_lookup: None,
+        enterprise: sub_m.get_flag("enterprise"),
+        smart_default: get_string(sub_m, "smart-default"),
+        key_value: get_string(sub_m, "key-value"),
+        id_collection: get_string(sub_m, "id-collection"),
+        drop_id: sub_m.get_flag("drop-id"),
+        column_renames: get_column_renames(sub_m),
+        header: !sub_m.get_flag("no-header"),
+        columns: get_columns(sub_m),
+        output: get_output_format(sub_m),
+    }
+}
+
+fn get_edge_options(sub_m: &ArgMatches) -> EdgeOptions {
+    let (data_type, csv) = get_format(sub_m);
+
+    let on_unresolved_str = sub_m.get_one::<String>("on-unresolved").unwrap();
+    let Some(on_unresolved) = parse_unresolved_policy(on_unresolved_str) else {
+        eprintln!("Invalid value '{}' for --on-unresolved.", on_unresolved_str);
+        std::process::exit(-1);
+    };
+
+    let mut collection_map = HashMap::new();
+    for m in sub_m
+        .get_many::<String>("map-collection")
+        .unwrap_or_default()
+    {
+        match m.split_once('=') {
+            Some((old, new)) if !old.is_empty() && !new.is_empty() => {
+                collection_map.insert(old.to_string(), new.to_string());
+            }
+            _ => {
+                eprintln!("Invalid value '{}' for --map-collection.", m);
+                std::process::exit(-1);
+            }
+        }
+    }
+
+    EdgeOptions {
+        data_type,
+        csv,
+        smart_index: get_smart_index(sub_m),
+        smart_regex: get_smart_regex(sub_m),
+        // only loaded for the `edges` subcommand, see `main`
+        smart_map: None,
+        enterprise: sub_m.get_flag("enterprise"),
+        on_unresolved,
+        disjoint: sub_m
+            .get_one::<String>("disjoint")
+            .and_then(|s| parse_disjoint_policy(s)),
+        collection_map,
+        from_attr: get_string(sub_m, "from-attribute"),
+        to_attr: get_string(sub_m, "to-attribute"),
+        header: !sub_m.get_flag("no-header"),
+        columns: Vec::new(),
+        output: get_output_format(sub_m),
+    }
+}
+
+/// Reads the options for the `smartjoin` subcommand: the options for the
+/// child collection and the ones for reading the parent collections.
+fn get_smartjoin_options(sub_m: &ArgMatches) -> (VertexOptions, EdgeOptions) {
+    let (data_type, csv) = get_format(sub_m);
+    let on_unresolved_str = sub_m.get_one::<String>("on-unresolved").unwrap();
+    let on_unresolved = match parse_unresolved_policy(on_unresolved_str) {
+        Some(p @ (UnresolvedPolicy::Error | UnresolvedPolicy::Default(_))) => p,
+        _ => {
+            eprintln!("Invalid value '{}' for --on-unresolved.", on_unresolved_str);
+            std::process::exit(-1);
+        }
+    };
+    let parent_opts = EdgeOptions {
+        data_type,
+        csv: csv.clone(),
+        smart_index: -1,
+        smart_regex: None,
+        smart_map: None,
+        enterprise: false,
+        on_unresolved,
+        disjoint: None,
+        collection_map: HashMap::new(),
+        from_attr: "_from".to_string(),
+        to_attr: "_to".to_string(),
+        // the parent files are smartified outputs, `--no-header` only
+        // applies to the input, a parent can say `header=false` itself
+        header: true,
+        columns: Vec::new(),
+        output: OutputFormat::default(),
+    };
+    let opts = VertexOptions {
+        smart_attr: get_string(sub_m, "smart-join-attribute"),
+        data_type,
+        csv,
+        write_key: true,
+        smart_value: get_string(sub_m, "parent-attribute"),
+        smart_index: -1,
+        smart_regex: None,
+        smart_expr: None,
+        key_expr: get_expr(sub_m, "key-expr"),
+        smart_bucket: None,
+        smart_map: None,
+        // set in `do_smartjoin` once the parents are loaded
+        parent_lookup: None,
+        enterprise: false,
+        smart_default: String::new(),
+        key_value: get_string(sub_m, "key-value"),
+        id_collection: get_string(sub_m, "id-collection"),
+        drop_id: sub_m.get_flag("drop-id"),
+        column_renames: get_column_renames(sub_m),
+        header: !sub_m.get_flag("no-header"),
+        columns: get_columns(sub_m),
+        output: get_output_format(sub_m),
+    };
+    (opts, parent_opts)
+}
+
+/// Parses a comma separated list of column names, all names must be
+/// non-empty.
+fn parse_column_list(s: &str) -> Option<Vec<String>> {
+    let columns: Vec<String> = s.split(',').map(|c| c.to_string()).collect();
+    if columns.iter().any(|c| c.is_empty()) {
+        return None;
+    }
+    Some(columns)
+}
+
+fn parse_conflict_policy(s: &str) -> Option<ConflictPolicy> {
+    match s {
+        "first" => Some(ConflictPolicy::FirstWins),
+        "last" => Some(ConflictPolicy::LastWins),
+        "error" => Some(ConflictPolicy::Error),
+        _ => None,
+    }
+}
+
+fn get_conflict_policy(sub_m: &ArgMatches) -> ConflictPolicy {
+    parse_conflict_policy(sub_m.get_one::<String>("on-conflict").unwrap()).unwrap()
+}
+
+/// Parses the `--vertices` and `--edges` specifications, exits on errors.
+fn get_collections(sub_m: &ArgMatches) -> (Vec<VertexCollection>, Vec<EdgeCollection>) {
+    let vertices_list: Vec<String> = sub_m
+        .get_many::<String>("vertices")
+        .unwrap_or_default()
+        .cloned()
+        .collect();
+    let edges_list: Vec<String> = sub_m
+        .get_many::<String>("edges")
+        .unwrap()
+        .cloned()
+        .collect();
+    let (mut vertex_collections, mut edge_collections) =
+        match parse_vertex_collections(vertices_list)
+            .and_then(|v| Ok((v, parse_edge_collections(edges_list)?)))
+        {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(-1);
+            }
+        };
+    if let Err(e) = add_vertex_refs(sub_m, &mut vertex_collections) {
+        eprintln!("{}", e);
+        std::process::exit(-1);
+    }
+    let type_given = from_command_line(sub_m, "type");
+    let sep_given = from_command_line(sub_m, "separator");
+    for v in &mut vertex_collections {
+        apply_detected_format(
+            &v.file_name,
+            &mut v.data_type,
+            &mut v.sep,
+            type_given,
+            sep_given,
+        );
+    }
+    for e in &mut edge_collections {
+        apply_detected_format(
+            &e.file_name,
+            &mut e.data_type,
+            &mut e.sep,
+            type_given,
+            sep_given,
+        );
+    }
+    (vertex_collections, edge_collections)
+}
+
+/// Tells if an option was given explicitly on the command line, as opposed
+/// to its default value.
+fn from_command_line(sub_m: &ArgMatches, name: &str) -> bool {
+    sub_m.value_source(name) == Some(ValueSource::CommandLine)
+}
+
+/// Takes the data type and the separator of the input file from its
+/// extension, unless they were given on the command line.
+fn apply_input_format(sub_m: &ArgMatches, input: &str, opts: &mut VertexOptions) {
+    let (data_type, sep) = detect_format(input);
+    if !from_command_line(sub_m, "type") {
+        opts.data_type = data_type.unwrap_or(opts.data_type);
+    }
+    override_with(
+        &mut opts.csv.sep,
+        sep,
+        from_command_line(sub_m, "separator"),
+    );
+}
+
+// -----------------------------------------------------------------------------
+// Pipeline configuration file for the `run` subcommand
+// -----------------------------------------------------------------------------
+
+/// A whole graph as described in a JSON configuration file. The global
+/// settings are optional and have the same meaning as the command line
+/// options of the same name.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct GraphConfig {
+    smart_graph_attribute: Option<String>,
+    #[serde(rename = "type")]
+    data_type: Option<String>,
+    separator: Option<String>,
+    quote_char: Option<String>,
+    escape: Option<String>,
+    write_key: Option<bool>,
+    smart_value: Option<String>,
+    smart_index: Option<i32>,
+    smart_regex: Option<String>,
+    smart_expr: Option<String>,
+    key_expr: Option<String>,
+    smart_geohash: Option<usize>,
+    geo_attributes: Option<Vec<String>>,
+    smart_bucket: Option<String>,
+    date_format: Option<String>,
+    smart_map: Option<String>,
+    smart_map_fallback: Option<String>,
+    enterprise: Option<bool>,
+    smart_default: Option<String>,
+    key_value: Option<String>,
+    id_collection: Option<String>,
+    drop_id: Option<bool>,
+    on_conflict: Option<String>,
+    on_unresolved: Option<String>,
+    disjoint: Option<String>,
+    map_collection: Option<HashMap<String, String>>,
+    from_attribute: Option<String>,
+    to_attribute: Option<String>,
+    header: Option<bool>,
+    columns: Option<Vec<String>>,
+    output_type: Option<String>,
+    infer_types: Option<bool>,
+    output_columns: Option<Vec<String>>,
+    vertices: Vec<VertexConfig>,
+    #[serde(default)]
+    edges: Vec<EdgeConfig>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct VertexConfig {
+    collection: String,
+    input: String,
+    output: String,
+    #[serde(rename = "type")]
+    data_type: Option<String>,
+    separator: Option<String>,
+    quote_char: Option<String>,
+    escape: Option<String>,
+    header: Option<bool>,
+    columns: Option<Vec<String>>,
+    smart_value: Option<String>,
+    smart_index: Option<i32>,
+    smart_regex: Option<String>,
+    smart_expr: Option<String>,
+    key_expr: Option<String>,
+    smart_geohash: Option<usize>,
+    geo_attributes: Option<Vec<String>>,
+    smart_bucket: Option<String>,
+    date_format: Option<String>,
+    key_value: Option<String>,
+    id_collection: Option<String>,
+    renames: Option<BTreeMap<usize, String>>,
+    #[serde(default)]
+    references: Vec<ReferenceConfig>,
+    #[serde(default)]
+    satellite: bool,
+}
+
+/// A reference attribute of a vertex collection, the collection of the
+/// referenced vertices defaults to the vertex collection itself.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct ReferenceConfig {
+    attribute: String,
+    collection: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct EdgeConfig {
+    input: String,
+    from: Vec<String>,
+    to: Vec<String>,
+    #[serde(rename = "type")]
+    data_type: Option<String>,
+    separator: Option<String>,
+    quote_char: Option<String>,
+    escape: Option<String>,
+    header: Option<bool>,
+    columns: Option<Vec<String>>,
+    from_attribute: Option<String>,
+    to_attribute: Option<String>,
+    #[serde(default)]
+    renames: BTreeMap<usize, String>,
+}
+
+/// Everything the `run` subcommand needs, built from the configuration
+/// file and the command line.
+struct Pipeline {
+    vertex_opts: VertexOptions,
+    edge_opts: EdgeOptions,
+    on_conflict: ConflictPolicy,
+    vertex_collections: Vec<VertexCollection>,
+    edge_collections: Vec<EdgeCollection>,
+}
+
+fn load_config(file_name: &str) -> Result<GraphConfig, String> {
+    let file = File::open(file_name)
+        .map_err(|e| format!("Cannot open config file {}: {}", file_name, e))?;
+    serde_json::from_reader(BufReader::new(file))
+        .map_err(|e| format!("Cannot parse config file {}: {}", file_name, e))
+}
+
+fn parse_data_type(s: &str) -> Result<DataType, String> {
+    match s.to_lowercase().as_str() {
+        "csv" => Ok(DataType::Csv),
+        "jsonl" => Ok(DataType::Jsonl),
+        _ => Err(format!("Invalid data type '{}', must be csv or jsonl.", s)),
+    }
+}
+
+/// Parses a single character like the quote character, `tab` can be
+/// used for a tab.
+fn parse_char(s: &str, what: &str) -> Result<char, String> {
+    if s == "tab" {
+        return Ok('\t');
+    }
+    let mut chars = s.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Ok(c),
+        (None, _) => Err(format!("Empty {}.", what)),
+        (Some(_), Some(_)) => Err(format!(
+            "Invalid {} '{}', must be a single character.",
+            what, s
+        )),
+    }
+}
+
+/// Parses a CSV separator, which can consist of multiple characters,
+/// `tab` can be used for a tab.
+fn parse_separator(s: &str) -> Result<String, String> {
+    match s {
+        "" => Err("Empty separator.".to_string()),
+        "tab" => Ok("\t".to_string()),
+        _ => Ok(s.to_string()),
+    }
+}
+
+fn parse_escape_style(s: &str) -> Result<EscapeStyle, String> {
+    match s {
+        "double" => Ok(EscapeStyle::Double),
+        "backslash" => Ok(EscapeStyle::Backslash),
+        "none" => Ok(EscapeStyle::None),
+        _ => Err(format!(
+            "Invalid escape style '{}', must be double, backslash or none.",
+            s
+        )),
+    }
+}
+
+fn parse_regex(s: &str) -> Result<Regex, String> {
+    Regex::new(s).map_err(|e| format!("Invalid regular expression '{}': {}", s, e))
+}
+
+fn parse_bool(s: &str) -> Result<bool, String> {
+    match s {
+        "true" | "yes" => Ok(true),
+        "false" | "no" => Ok(false),
+        _ => Err(format!("Invalid boolean '{}', must be true or false.", s)),
+    }
+}
+
+fn check_column_list(columns: &[String]) -> Result<(), String> {
+    if columns.is_empty() || columns.iter().any(|c| c.is_empty()) {
+        return Err("Column lists must not be empty or contain empty names.".to_string());
+    }
+    Ok(())
+}
+
+/// Builds the geohash expression for `smartGeohash` and `geoAttributes` in
+/// the configuration file, which cannot be combined with `smartExpr`.
+fn config_geohash(
+    precision: Option<usize>,
+    names: Option<Vec<String>>,
+    has_expr: bool,
+) -> Result<Option<Expr>, String> {
+    let Some(precision) = precision else {
+        return match names {
+            Some(_) => Err("geoAttributes needs smartGeohash.".to_string()),
+            None => Ok(None),
+        };
+    };
+    if has_expr {
+        return Err("smartGeohash cannot be combined with smartExpr.".to_string());
+    }
+    let names = names.ok_or_else(|| "smartGeohash needs geoAttributes.".to_string())?;
+    geohash_attributes(precision, &names).map(Some)
+}
+
+/// Sets `target` to the configured value, unless the option was given on
+/// the command line.
+fn override_with<T>(target: &mut T, value: Option<T>, cli: bool) {
+    if let (false, Some(v)) = (cli, value) {
+        *target = v;
+    }
+}
+
+/// A global collection name for `_id` cannot be right for more than one
+/// collection, so graph and run mode reject it.
+const ID_COLLECTION_ERROR: &str = "--id-collection cannot be used in graph and run mode, \
+     set idCollection for each vertex collection in a configuration file for run mode instead.";
+
+/// Combines the configuration file with the command line. Options given
+/// explicitly on the command line take precedence over the configuration,
+/// both for the global settings and for the settings of each collection.
+fn build_pipeline(config: GraphConfig, sub_m: &ArgMatches) -> Result<Pipeline, String> {
+    let cli = |name: &str| from_command_line(sub_m, name);
+    let type_given = cli("type") || config.data_type.is_some();
+    let sep_given = cli("separator") || config.separator.is_some();
+    if cli("id-collection") || config.id_collection.is_some() {
+        return Err(ID_COLLECTION_ERROR.to_string());
+    }
+    let mut vertex_opts = get_vertex_options(sub_m);
+    let mut edge_opts = get_edge_options(sub_m);
+    let mut on_conflict = get_conflict_policy(sub_m);
+
+    let data_type = config
+        .data_type
+        .as_deref()
+        .map(parse_data_type)
+        .transpose()?;
+    let sep = config
+        .separator
+        .as_deref()
+        .map(parse_separator)
+        .transpose()?;
+    let quo = config
+        .quote_char
+        .as_deref()
+        .map(|s| parse_char(s, "quote character"))
+        .transpose()?;
+    let esc = config
+        .escape
+        .as_deref()
+        .map(parse_escape_style)
+        .transpose()?;
+    for (t, csv) in [
+        (&mut vertex_opts.data_type, &mut vertex_opts.csv),
+        (&mut edge_opts.data_type, &mut edge_opts.csv),
+    ] {
+        override_with(t, data_type, cli("type"));
+        override_with(&mut csv.sep, sep.clone(), cli("separator"));
+        override_with(&mut csv.quo, quo, cli("quote-char"));
+        override_with(&mut csv.esc, esc, cli("escape"));
+    }
+    override_with(&mut vertex_opts.header, config.header, cli("no-header"));
+    override_with(&mut edge_opts.header, config.header, cli("no-header"));
+    if let Some(columns) = &config.columns {
+        check_column_list(columns)?;
+    }
+    override_with(&mut vertex_opts.columns, config.columns, cli("columns"));
+    let output_type = config
+        .output_type
+        .as_deref()
+        .map(parse_data_type)
+        .transpose()?;
+    if let Some(columns) = &config.output_columns {
+        check_column_list(columns)?;
+    }
+    for output in [&mut vertex_opts.output, &mut edge_opts.output] {
+        override_with(
+            &mut output.data_type,
+            output_type.map(Some),
+            cli("output-type"),
+        );
+        override_with(
+            &mut output.infer_types,
+            config.infer_types,
+            cli("infer-types"),
+        );
+        override_with(
+            &mut output.columns,
+            config.output_columns.clone(),
+            cli("output-columns"),
+        );
+    }
+
+    override_with(
+        &mut vertex_opts.smart_attr,
+        config.smart_graph_attribute,
+        cli("smart-graph-attribute"),
+    );
+    override_with(
+        &mut vertex_opts.write_key,
+        config.write_key,
+        cli("write-key"),
+    );
+    override_with(
+        &mut vertex_opts.smart_value,
+        config.smart_value,
+        cli("smart-value"),
+    );
+    override_with(
+        &mut vertex_opts.smart_index,
+        config.smart_index,
+        cli("smart-index"),
+    );
+    override_with(
+        &mut vertex_opts.smart_regex,
+        config
+            .smart_regex
+            .as_deref()
+            .map(parse_regex)
+            .transpose()?
+            .map(Some),
+        cli("smart-regex"),
+    );
+    override_with(
+        &mut vertex_opts.smart_expr,
+        config
+            .smart_expr
+            .as_deref()
+            .map(parse_template)
+            .transpose()?
+            .map(Some),
+        cli("smart-expr"),
+    );
+    let geohash = config_geohash(
+        config.smart_geohash,
+        config.geo_attributes,
+        config.smart_expr.is_some(),
+    )?;
+    override_with(
+        &mut vertex_opts.smart_expr,
+        geohash.map(Some),
+        cli("smart-expr") || cli("smart-geohash"),
+    );
+    override_with(
+        &mut vertex_opts.key_expr,
+        config
+            .key_expr
+            .as_deref()
+            .map(parse_template)
+            .transpose()?
+            .map(Some),
+        cli("key-expr"),
+    );
+    // The bucket is parsed again, since its date format can come from the
+    // configuration file.
+    let mut date_format = get_string(sub_m, "date-format");
+    override_with(&mut date_format, config.date_format, cli("date-format"));
+    let mut smart_bucket = sub_m.get_one::<String>("smart-bucket").cloned();
+    override_with(
+        &mut smart_bucket,
+        config.smart_bucket.map(Some),
+        cli("smart-bucket"),
+    );
+    vertex_opts.smart_bucket = smart_bucket
+        .as_deref()
+        .map(|spec| parse_bucket(spec, &date_format))
+        .transpose()?;
+    if let (Some(file_name), false) = (&config.smart_map, cli("smart-map")) {
+        let fallback_str = match config.smart_map_fallback {
+            Some(s) if !cli("smart-map-fallback") => s,
+            _ => get_string(sub_m, "smart-map-fallback"),
+        };
+        let fallback = parse_map_fallback(&fallback_str)
+            .ok_or_else(|| format!("Invalid value '{}' for smartMapFallback.", fallback_str))?;
+        let mut csv = vertex_opts.csv.clone();
+        override_with(&mut csv.sep, detect_format(file_name).1, sep_given);
+        let map = SmartMap::load(file_name, &csv, fallback)?;
+        vertex_opts.smart_map = Some(Arc::new(map));
+    }
+    override_with(
+        &mut vertex_opts.smart_default,
+        config.smart_default,
+        cli("smart-default"),
+    );
+    override_with(
+        &mut vertex_opts.key_value,
+        config.key_value,
+        cli("key-value"),
+    );
+    override_with(&mut vertex_opts.drop_id, config.drop_id, cli("drop-id"));
+    override_with(
+        &mut vertex_opts.enterprise,
+        config.enterprise,
+        cli("enterprise"),
+    );
+    edge_opts.enterprise = vertex_opts.enterprise;
+
+    if let Some(s) = config.on_conflict {
+        let policy = parse_conflict_policy(&s)
+            .ok_or_else(|| format!("Invalid value '{}' for onConflict.", s))?;
+        override_with(&mut on_conflict, Some(policy), cli("on-conflict"));
+    }
+    if let Some(s) = config.on_unresolved {
+        let policy = parse_unresolved_policy(&s)
+            .ok_or_else(|| format!("Invalid value '{}' for onUnresolved.", s))?;
+        override_with(
+            &mut edge_opts.on_unresolved,
+            Some(policy),
+            cli("on-unresolved"),
+        );
+    }
+    if let Some(s) = config.disjoint {
+        let policy = parse_disjoint_policy(&s)
+            .ok_or_else(|| format!("Invalid value '{}' for disjoint.", s))?;
+        override_with(&mut edge_opts.disjoint, Some(Some(policy)), cli("disjoint"));
+    }
+    override_with(
+        &mut edge_opts.collection_map,
+        config.map_collection,
+        cli("map-collection"),
+    );
+    override_with(
+        &mut edge_opts.from_attr,
+        config.from_attribute,
+        cli("from-attribute"),
+    );
+    override_with(
+        &mut edge_opts.to_attr,
+        config.to_attribute,
+        cli("to-attribute"),
+    );
+    // --smart-index, --smart-regex and --smart-map apply to the vertices
+    // here, see the `graph` subcommand
+    edge_opts.smart_index = -1;
+    edge_opts.smart_regex = None;
+
+    let mut vertex_collections = Vec::new();
+    for v in config.vertices {
+        let mut coll = VertexCollection {
+            coll_name: v.collection,
+            file_name: v.input,
+            output_file: Some(v.output),
+            satellite: v.satellite,
+            ..Default::default()
+        };
+        if !cli("type") {
+            coll.data_type = v.data_type.as_deref().map(parse_data_type).transpose()?;
+        }
+        if !cli("separator") {
+            coll.sep = v.separator.as_deref().map(parse_separator).transpose()?;
+        }
+        if !cli("quote-char") {
+            coll.quo = v
+                .quote_char
+                .as_deref()
+                .map(|s| parse_char(s, "quote character"))
+                .transpose()?;
+        }
+        if !cli("escape") {
+            coll.esc = v.escape.as_deref().map(parse_escape_style).transpose()?;
+        }
+        if !cli("no-header") {
+            coll.header = v.header;
+        }
+        if let Some(columns) = &v.columns {
+            check_column_list(columns)?;
+        }
+        if !cli("columns") {
+            coll.columns = v.columns;
+        }
+        apply_detected_format(
+            &coll.file_name,
+            &mut coll.data_type,
+            &mut coll.sep,
+            type_given,
+            sep_given,
+        );
+        if !cli("smart-value") {
+            coll.smart_value = v.smart_value;
+        }
+        if !cli("smart-index") {
+            coll.smart_index = v.smart_index;
+        }
+        if !cli("smart-regex") {
+            coll.smart_regex = v.smart_regex.as_deref().map(parse_regex).transpose()?;
+        }
+        if !cli("smart-expr") {
+            coll.smart_expr = v.smart_expr.as_deref().map(parse_template).transpose()?;
+        }
+        let geohash = config_geohash(v.smart_geohash, v.geo_attributes, v.smart_expr.is_some())?;
+        if !cli("smart-expr") && !cli("smart-geohash") && geohash.is_some() {
+            coll.smart_expr = geohash;
+        }
+        if !cli("key-expr") {
+            coll.key_expr = v.key_expr.as_deref().map(parse_template).transpose()?;
+        }
+        // an inherited bucket is parsed again with the date format of the
+        // collection
+        let coll_date_format = v.date_format.filter(|_| !cli("date-format"));
+        let coll_bucket = match v.smart_bucket {
+            Some(spec) if !cli("smart-bucket") => Some(spec),
+            _ if coll_date_format.is_some() => smart_bucket.clone(),
+            _ => None,
+        };
+        if let Some(spec) = coll_bucket {
+            let format = coll_date_format.as_deref().unwrap_or(&date_format);
+            coll.smart_bucket = Some(parse_bucket(&spec, format)?);
+        }
+        if !cli("key-value") {
+            coll.key_value = v.key_value;
+        }
+        coll.id_collection = v.id_collection;
+        if !cli("rename-column") {
+            coll.column_renames = v.renames.map(|r| r.into_iter().collect());
+        }
+        for r in v.references {
+            coll.references.push(VertexRef {
+                attr: r.attribute,
+                target: r.collection.unwrap_or_else(|| coll.coll_name.clone()),
+            });
+        }
+        vertex_collections.push(coll);
+    }
+    add_vertex_refs(sub_m, &mut vertex_collections)?;
+
+    let mut edge_collections = Vec::new();
+    for e in config.edges {
+        if e.from.is_empty() || e.to.is_empty() {
+            return Err(format!(
+                "Edge collection {} needs at least one from and one to collection.",
+                e.input
+            ));
+        }
+        let mut coll = EdgeCollection {
+            file_name: e.input,
+            from_vertex_colls: e.from,
+            to_vertex_colls: e.to,
+            column_renames: e.renames.into_iter().collect(),
+            ..Default::default()
+        };
+        if !cli("type") {
+            coll.data_type = e.data_type.as_deref().map(parse_data_type).transpose()?;
+        }
+        if !cli("separator") {
+            coll.sep = e.separator.as_deref().map(parse_separator).transpose()?;
+        }
+        if !cli("quote-char") {
+            coll.quo = e
+                .quote_char
+                .as_deref()
+                .map(|s| parse_char(s, "quote character"))
+                .transpose()?;
+        }
+        if !cli("escape") {
+            coll.esc = e.escape.as_deref().map(parse_escape_style).transpose()?;
+        }
+        if !cli("no-header") {
+            coll.header = e.header;
+        }
+        if let Some(columns) = &e.columns {
+            check_column_list(columns)?;
+        }
+        coll.columns = e.columns;
+        apply_detected_format(
+            &coll.file_name,
+            &mut coll.data_type,
+            &mut coll.sep,
+            type_given,
+            sep_given,
         );
+        if !cli("from-attribute") {
+            coll.from_attr = e.from_attribute;
+        }
+        if !cli("to-attribute") {
+            coll.to_attr = e.to_attribute;
+        }
+        edge_collections.push(coll);
     }
+
+    Ok(Pipeline {
+        vertex_opts,
+        edge_opts,
+        on_conflict,
+        vertex_collections,
+        edge_collections,
+    })
 }
 
 // -----------------------------------------------------------------------------
@@ -425,15 +3452,88 @@ fn main() {
         .about("Transform graph data into smart graph format (Rust version)")
         .subcommand_required(true)
         .subcommand(
-            Command::new("vertices")
-                .about("Transform vertices into smart graph format")
+            Command::new("vertices")
+                .about("Transform vertices into smart graph format")
+                .arg(
+                    Arg::new("input")
+                        .long("input")
+                        .short('i')
+                        .num_args(1)
+                        .required(true)
+                        .help("Input file (CSV or JSONL)"),
+                )
+                .arg(
+                    Arg::new("output")
+                        .long("output")
+                        .short('o')
+                        .num_args(1)
+                        .required(true)
+                        .help("Output file (CSV or JSONL)"),
+                )
+                .args(format_args())
+                .args(vertex_args()),
+        )
+        .subcommand(
+            Command::new("edges")
+                .about("Transform edges into smart graph format")
+                .args(format_args())
+                .arg(edges_arg())
+                .args(edge_args())
+                .arg(
+                    Arg::new("smart-index")
+                        .long("smart-index")
+                        .num_args(1)
+                        .help("If >0, take this many chars from the key for smart attribute"),
+                )
+                .arg(
+                    Arg::new("smart-regex")
+                        .long("smart-regex")
+                        .num_args(1)
+                        .conflicts_with("smart-index")
+                        .help("Regular expression, its first capture group in the key is the smart graph attribute value"),
+                )
+                .arg(smart_map_arg())
+                .arg(smart_map_fallback_arg())
+                .arg(
+                    Arg::new("enterprise")
+                        .long("enterprise")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with_all(["smart-index", "smart-regex", "smart-map"])
+                        .help("EnterpriseGraph, the key prefixes are computed from the keys without vertex data"),
+                )
+                .arg(
+                    Arg::new("vertices")
+                        .long("vertices")
+                        .num_args(..)
+                        .required_unless_present("enterprise")
+                        .help("One ore more vertex specifications: <vertexcoll>:<vertexfile>"),
+                ),
+        )
+        .subcommand(
+            Command::new("graph")
+                .about("Transform vertices and edges into smart graph format in one go")
+                .args(format_args())
+                .args(vertex_args())
+                .arg(edges_arg())
+                .args(edge_args())
+                .arg(
+                    Arg::new("vertices")
+                        .long("vertices")
+                        .num_args(..)
+                        .required(true)
+                        .help("One ore more vertex specifications: <vertexcoll>:<inputfile>:<outputfile>"),
+                ),
+        )
+        .subcommand(
+            Command::new("smartjoin")
+                .about("Transform a child document collection for SmartJoins with its parent collection")
                 .arg(
                     Arg::new("input")
                         .long("input")
                         .short('i')
                         .num_args(1)
                         .required(true)
-                        .help("Input file (CSV or JSONL)"),
+                        .help("Input file with the child documents (CSV or JSONL)"),
                 )
                 .arg(
                     Arg::new("output")
@@ -443,105 +3543,67 @@ fn main() {
                         .required(true)
                         .help("Output file (CSV or JSONL)"),
                 )
+                .args(format_args())
+                .args(vertex_args().into_iter().filter(|a| {
+                    [
+                        "key-value",
+                        "key-expr",
+                        "id-collection",
+                        "drop-id",
+                        "rename-column",
+                        "columns",
+                    ]
+                    .contains(&a.get_id().as_str())
+                }))
                 .arg(
-                    Arg::new("smart-graph-attribute")
-                        .long("smart-graph-attribute")
-                        .num_args(1)
-                        .default_value("smart_id")
-                        .help("Name of the smart graph attribute"),
-                )
-                .arg(
-                    Arg::new("type")
-                        .long("type")
-                        .num_args(1)
-                        .default_value("csv")
-                        .help("Input data type: csv or jsonl"),
-                )
-                .arg(
-                    Arg::new("separator")
-                        .long("separator")
-                        .default_value(",")
-                        .help("Column separator for CSV"),
-                )
-                .arg(
-                    Arg::new("quote-char")
-                        .long("quote-char")
-                        .default_value("\"")
-                        .help("Quote character for CSV"),
-                )
-                .arg(
-                    Arg::new("write-key")
-                        .long("write-key")
-                        .action(ArgAction::SetTrue)
-                        .help("If present, the `_key` attribute will be re-written"),
+                    Arg::new("parents")
+                        .long("parents")
+                        .num_args(1..)
+                        .required(true)
+                        .help("One or more smartified parent collections: <parentcoll>:<parentfile>"),
                 )
                 .arg(
-                    Arg::new("smart-value")
-                        .long("smart-value")
+                    Arg::new("parent-attribute")
+                        .long("parent-attribute")
                         .num_args(1)
-                        .help("Attribute/column used to build the smart graph attribute"),
+                        .required(true)
+                        .help("Attribute/column with the key of the parent document"),
                 )
                 .arg(
-                    Arg::new("smart-index")
-                        .long("smart-index")
+                    Arg::new("smart-join-attribute")
+                        .long("smart-join-attribute")
                         .num_args(1)
-                        .help("If given, only this many characters are taken from the smart value"),
+                        .required(true)
+                        .help("Name of the smartJoinAttribute, set to the smart value of the parent"),
                 )
                 .arg(
-                    Arg::new("smart-default")
-                        .long("smart-default")
+                    Arg::new("parent-collection")
+                        .long("parent-collection")
                         .num_args(1)
-                        .help("Default value for smart graph attribute if not present (JSONL only)"),
+                        .help("Parent collection for keys without a collection prefix, default is the first one in --parents"),
                 )
                 .arg(
-                    Arg::new("key-value")
-                        .long("key-value")
+                    Arg::new("on-unresolved")
+                        .long("on-unresolved")
                         .num_args(1)
-                        .help("Column/attribute name from which to get the value for `_key` suffix"),
-                )
+                        .default_value("error")
+                        .help("What to do with documents whose parent is not found: error or default:<value>"),
+                ),
         )
         .subcommand(
-            Command::new("edges")
-                .about("Transform edges into smart graph format")
-                .arg(
-                    Arg::new("type")
-                        .long("type")
-                        .num_args(1)
-                        .default_value("csv")
-                        .help("Input data type: csv or jsonl"),
-                )
-                .arg(
-                    Arg::new("separator")
-                        .long("separator")
-                        .default_value(",")
-                        .help("Column separator for CSV"),
-                )
-                .arg(
-                    Arg::new("quote-char")
-                        .long("quote-char")
-                        .default_value("\"")
-                        .help("Quote character for CSV"),
-                )
-                .arg(
-                    Arg::new("edges")
-                        .long("edges")
-                        .num_args(..)
-                        .required(true)
-                        .help("One or more edge specifications: <edgefile>:<fromColl>:<toColl>[:<colIndex>:<newName> ...]"),
-                )
+            Command::new("run")
+                .about("Transform a whole graph as described in a configuration file")
                 .arg(
-                    Arg::new("smart-index")
-                        .long("smart-index")
+                    Arg::new("config")
+                        .long("config")
+                        .short('c')
                         .num_args(1)
-                        .help("If >0, take this many chars from the key for smart attribute"),
-                )
-                .arg(
-                    Arg::new("vertices")
-                        .long("vertices")
-                        .num_args(..)
                         .required(true)
-                        .help("One ore more vertex specifications: <vertexcoll>:<vertexfile>"),
+                        .help("JSON configuration file describing the graph"),
                 )
+                .args(format_args())
+                .args(vertex_args())
+                .args(edge_args()),
         )
         .get_matches();
 
@@ -549,107 +3611,99 @@ fn main() {
         Some(("vertices", sub_m)) => {
             let input = sub_m.get_one::<String>("input").unwrap().clone();
             let output = sub_m.get_one::<String>("output").unwrap().clone();
-            let smart_attr = sub_m
-                .get_one::<String>("smart-graph-attribute")
-                .unwrap()
-                .clone();
-            let data_type_str = sub_m.get_one::<String>("type").unwrap().to_lowercase();
-            let data_type = if data_type_str == "jsonl" {
-                DataType::JSONL
-            } else {
-                DataType::CSV
-            };
-            let sep = sub_m
-                .get_one::<String>("separator")
-                .unwrap()
-                .chars()
-                .next()
-                .unwrap();
-            let quo = sub_m
-                .get_one::<String>("quote-char")
+            let mut opts = get_vertex_options(sub_m);
+            apply_input_format(sub_m, &input, &mut opts);
+            std::process::exit(do_vertices(&input, &output, &opts, None));
+        }
+        Some(("smartjoin", sub_m)) => {
+            let input = sub_m.get_one::<String>("input").unwrap().clone();
+            let output = sub_m.get_one::<String>("output").unwrap().clone();
+            let (mut opts, parent_opts) = get_smartjoin_options(sub_m);
+            apply_input_format(sub_m, &input, &mut opts);
+            let parents_list: Vec<String> = sub_m
+                .get_many::<String>("parents")
                 .unwrap()
-                .chars()
-                .next()
-                .unwrap();
-            let write_key = sub_m.get_flag("write-key");
-            let smart_value = sub_m
-                .get_one::<String>("smart-value")
-                .unwrap_or(&"".to_string())
-                .clone();
-            let smart_index_str = sub_m
-                .get_one::<String>("smart-index")
-                .unwrap_or(&"".to_string())
-                .clone();
-            let smart_index: i32 = if smart_index_str.is_empty() {
-                -1
-            } else {
-                smart_index_str.parse().unwrap_or(-1)
+                .cloned()
+                .collect();
+            let mut parents = match parse_vertex_collections(parents_list) {
+                Ok(p) => p,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(-1);
+                }
             };
-            let smart_default = sub_m
-                .get_one::<String>("smart-default")
-                .unwrap_or(&"".to_string())
-                .clone();
-            let key_value = sub_m
-                .get_one::<String>("key-value")
-                .unwrap_or(&"".to_string())
+            let type_given = from_command_line(sub_m, "type");
+            let sep_given = from_command_line(sub_m, "separator");
+            for p in &mut parents {
+                apply_detected_format(
+                    &p.file_name,
+                    &mut p.data_type,
+                    &mut p.sep,
+                    type_given,
+                    sep_given,
+                );
+            }
+            let parent_coll = sub_m
+                .get_one::<String>("parent-collection")
+                .unwrap_or(&parents[0].coll_name)
                 .clone();
-
-            std::process::exit(do_vertices(
+            std::process::exit(do_smartjoin(
                 &input,
                 &output,
-                &smart_attr,
-                data_type,
-                sep,
-                quo,
-                write_key,
-                &smart_value,
-                smart_index,
-                &smart_default,
-                &key_value,
+                opts,
+                parent_opts,
+                &parents,
+                &parent_coll,
             ));
         }
         Some(("edges", sub_m)) => {
-            let data_type_str = sub_m.get_one::<String>("type").unwrap().to_lowercase();
-            let data_type = if data_type_str == "jsonl" {
-                DataType::JSONL
-            } else {
-                DataType::CSV
-            };
-            let sep = sub_m
-                .get_one::<String>("separator")
-                .unwrap()
-                .chars()
-                .next()
-                .unwrap();
-            let quo = sub_m
-                .get_one::<String>("quote-char")
-                .unwrap()
-                .chars()
-                .next()
-                .unwrap();
-            let vertices_list = sub_m.get_many("vertices").unwrap();
-            let edges_list = sub_m.get_many::<String>("edges").unwrap();
-            let smart_index_str = sub_m
-                .get_one::<String>("smart-index")
-                .unwrap_or(&"".to_string())
-                .clone();
-            let smart_index: i32 = if smart_index_str.is_empty() {
-                -1
-            } else {
-                smart_index_str.parse().unwrap_or(-1)
-            };
-
-            let vertices_list: Vec<String> = vertices_list.map(|x: &String| x.clone()).collect();
-            let vertex_collections: Vec<VertexCollection> = parse_vertex_collections(vertices_list);
-            let edges_list: Vec<String> = edges_list.map(|x| x.clone()).collect();
-            let edge_collections = parse_edge_collections(edges_list);
+            let on_conflict = get_conflict_policy(sub_m);
+            let (vertex_collections, edge_collections) = get_collections(sub_m);
+            let mut opts = get_edge_options(sub_m);
+            opts.smart_map = get_smart_map(sub_m, &opts.csv);
             std::process::exit(do_edges(
-                data_type,
-                sep,
-                quo,
+                &opts,
+                &vertex_collections,
+                &edge_collections,
+                on_conflict,
+            ));
+        }
+        Some(("graph", sub_m)) => {
+            if from_command_line(sub_m, "id-collection") {
+                eprintln!("{}", ID_COLLECTION_ERROR);
+                std::process::exit(-1);
+            }
+            let (vertex_collections, edge_collections) = get_collections(sub_m);
+            let vertex_opts = get_vertex_options(sub_m);
+            let mut edge_opts = get_edge_options(sub_m);
+            // --smart-index, --smart-regex and --smart-map apply to the
+            // vertices here, the edges are always translated with the keys
+            // captured from the vertices.
+            edge_opts.smart_index = -1;
+            edge_opts.smart_regex = None;
+            std::process::exit(do_graph(
+                &vertex_opts,
+                &edge_opts,
                 &vertex_collections,
                 &edge_collections,
-                smart_index,
+                get_conflict_policy(sub_m),
+            ));
+        }
+        Some(("run", sub_m)) => {
+            let config_file = sub_m.get_one::<String>("config").unwrap();
+            let pipeline = match load_config(config_file).and_then(|c| build_pipeline(c, sub_m)) {
+                Ok(p) => p,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(-1);
+                }
+            };
+            std::process::exit(do_graph(
+                &pipeline.vertex_opts,
+                &pipeline.edge_opts,
+                &pipeline.vertex_collections,
+                &pipeline.edge_collections,
+                pipeline.on_conflict,
             ));
         }
         _ => {
@@ -663,19 +3717,26 @@ fn main() {
 // Implementation of do_vertices
 // -----------------------------
 
+/// Where `do_vertices` records the new vertex keys, such that the
+/// `graph` subcommand can rewrite the edges without reading the output.
+struct KeyCapture<'a> {
+    translation: &'a mut Translation,
+    coll_name: &'a str,
+    policy: ConflictPolicy,
+}
+
 fn do_vertices(
     input_file: &str,
     output_file: &str,
-    smart_attr: &str,
-    data_type: DataType,
-    sep: char,
-    quo: char,
-    write_key: bool,
-    smart_value: &str,
-    smart_index: i32,
-    smart_default: &str,
-    key_value: &str,
+    opts: &VertexOptions,
+    mut capture: Option<KeyCapture>,
 ) -> i32 {
+    let smart_attr = opts.smart_attr.as_str();
+    let smart_value = opts.smart_value.as_str();
+    let key_value = opts.key_value.as_str();
+    let write_key = opts.write_key;
+    let csv = &opts.csv;
+
     // open input
     let input = match File::open(input_file) {
         Ok(f) => f,
@@ -694,26 +3755,49 @@ fn do_vertices(
             return 2;
         }
     };
-    let mut writer = BufWriter::new(output);
+    let mut writer = RecordWriter::new(
+        BufWriter::new(output),
+        opts.data_type,
+        csv,
+        &opts.output,
+        &["_key", "_id", smart_attr],
+    );
+    let source = capture
+        .as_mut()
+        .map(|c| c.translation.add_source(c.coll_name, input_file))
+        .unwrap_or(0);
+    let mut capture_key = |key: Option<String>, line: u64| {
+        if let (Some(c), Some(key)) = (&mut capture, key) {
+            c.translation
+                .add_vertex_key(c.coll_name, &key, source, line, c.policy);
+        }
+    };
 
-    match data_type {
-        DataType::CSV => {
-            // We read the first line as the header:
+    match opts.data_type {
+        DataType::Csv => {
+            // We read the first line as the header (unless there is none):
             let mut lines = reader.lines();
-            let Some(Ok(header_line)) = lines.next() else {
+            let Some((mut col_headers, first_line)) =
+                read_csv_header(&mut lines, opts.header, &opts.columns, csv)
+            else {
                 eprintln!("Could not read header line in vertex file {}", input_file);
                 return 3;
             };
-
-            let mut col_headers = split(&header_line, sep, quo)
-                .into_iter()
-                .map(|s| unquote(&s, quo))
-                .collect::<Vec<String>>();
             let mut ncols = col_headers.len();
 
+            // rename columns if needed
+            for (col_idx, new_name) in &opts.column_renames {
+                if *col_idx < col_headers.len() {
+                    col_headers[*col_idx] = new_name.clone();
+                }
+            }
+
             // Try to find or create the column for the smart attribute
             let mut smart_attr_pos = find_col_pos(&col_headers, smart_attr);
-            if smart_attr_pos < 0 {
+            if opts.enterprise {
+                // EnterpriseGraphs have no smart graph attribute
+                smart_attr_pos = -1;
+            } else if smart_attr_pos < 0 {
                 smart_attr_pos = ncols as i32;
                 col_headers.push(smart_attr.to_string());
                 ncols += 1;
@@ -723,6 +3807,13 @@ fn do_vertices(
             let mut smart_value_pos = -1;
             if !smart_value.is_empty() {
                 smart_value_pos = find_col_pos(&col_headers, smart_value);
+                if smart_value_pos < 0 && opts.parent_lookup.is_some() {
+                    eprintln!(
+                        "Did not find parent column {} in file {}",
+                        smart_value, input_file
+                    );
+                    return 4;
+                }
                 if smart_value_pos < 0 {
                     eprintln!(
                         "Warning: could not find the smart value column {}. Ignoring...",
@@ -738,6 +3829,13 @@ fn do_vertices(
                 col_headers.push("_key".to_string());
                 ncols += 1;
             }
+            if key_pos < 0 {
+                eprintln!(
+                    "Did not find _key column in vertex file {}, use --write-key to create it",
+                    input_file
+                );
+                return 4;
+            }
 
             let mut key_value_pos = -1;
             if !key_value.is_empty() {
@@ -750,151 +3848,817 @@ fn do_vertices(
                 }
             }
 
-            // Write out the new header
-            if !col_headers.is_empty() {
-                write!(writer, "{}", quote_string(&col_headers[0], quo)).unwrap();
+            let id_pos = find_col_pos(&col_headers, "_id");
+            let mut smart_expr = opts.smart_expr.clone();
+            let mut key_expr = opts.key_expr.clone();
+            for expr in smart_expr.iter_mut().chain(key_expr.iter_mut()) {
+                if let Err(e) = expr.bind_columns(&col_headers) {
+                    eprintln!("{} in vertex file {}.", e, input_file);
+                    return 4;
+                }
             }
-            for c in &col_headers[1..] {
-                write!(writer, "{}", sep).unwrap();
-                write!(writer, "{}", quote_string(c, quo)).unwrap();
+            let cols = VertexColumns {
+                ncols,
+                smart_attr_pos,
+                smart_value_pos,
+                key_pos,
+                key_value_pos,
+                id_pos,
+                smart_expr,
+                key_expr,
+            };
+            if id_pos >= 0 && opts.drop_id {
+                col_headers.remove(id_pos as usize);
             }
-            writeln!(writer).unwrap();
 
-            let mut count: u64 = 1;
-            for line_result in lines {
+            // Write out the new header
+            writer.write_header(&col_headers);
+
+            let mut count: u64 = if opts.header { 1 } else { 0 };
+            for line_result in first_line.map(Ok).into_iter().chain(lines) {
                 let Ok(line_str) = line_result else {
                     continue; // skip ill-formed lines
                 };
-                transform_vertex_csv(
-                    &line_str,
-                    count + 1,
-                    sep,
-                    quo,
-                    ncols,
-                    smart_attr_pos,
-                    smart_value_pos,
-                    smart_index,
-                    key_pos,
-                    key_value_pos,
-                    &mut writer,
-                );
+                let key = match transform_vertex_csv(&line_str, count + 1, opts, &cols, &mut writer)
+                {
+                    Ok(key) => key,
+                    Err(code) => {
+                        drop(writer);
+                        std::fs::remove_file(output_file).ok();
+                        return code;
+                    }
+                };
+                capture_key(key, count + 1);
                 count += 1;
-                if count % 1_000_000 == 0 {
+                if count.is_multiple_of(1_000_000) {
                     println!("{:.3} Have transformed {} vertices.", elapsed(), count);
                 }
             }
         }
-        DataType::JSONL => {
+        DataType::Jsonl => {
+            let paths = VertexPaths::new(opts);
             let mut count = 1;
             for line_result in reader.lines() {
                 let Ok(line_str) = line_result else {
                     continue;
                 };
-                transform_vertex_jsonl(
-                    &line_str,
-                    count,
-                    smart_attr,
-                    smart_value,
-                    smart_index,
-                    smart_default,
-                    write_key,
-                    key_value,
-                    &mut writer,
-                );
+                let key = match transform_vertex_jsonl(&line_str, count, opts, &paths, &mut writer)
+                {
+                    Ok(key) => key,
+                    Err(code) => {
+                        drop(writer);
+                        std::fs::remove_file(output_file).ok();
+                        return code;
+                    }
+                };
+                capture_key(key, count as u64);
                 count += 1;
-                if count % 1_000_000 == 0 {
+                if count.is_multiple_of(1_000_000) {
                     println!("{:.3} Have transformed {} vertices.", elapsed(), count);
                 }
             }
         }
     }
 
-    // Make sure we flush and close properly
-    if let Err(e) = writer.flush() {
-        eprintln!("Error flushing output file {}: {}", output_file, e);
-        return 4;
+    // Make sure we flush and close properly
+    if let Err(e) = writer.finish() {
+        eprintln!("Error flushing output file {}: {}", output_file, e);
+        return 4;
+    }
+    0
+}
+
+// ------------------------------------------
+// do_edges: rewrite edges for smartification
+// ------------------------------------------
+
+/// Splits a structured collection spec of the form `key=value,key=value`
+/// into its pairs. A backslash escapes the next character, such that keys
+/// and values can contain `,`, `=`, `:` and `\`.
+fn parse_spec_pairs(spec: &str) -> Result<Vec<(String, String)>, String> {
+    let mut pairs = Vec::new();
+    let mut key = String::new();
+    let mut value = String::new();
+    let mut in_value = false;
+    let mut chars = spec.chars();
+    while let Some(c) = chars.next() {
+        let cur = if in_value { &mut value } else { &mut key };
+        match c {
+            '\\' => match chars.next() {
+                Some(escaped) => cur.push(escaped),
+                None => return Err(format!("Trailing backslash in spec '{}'.", spec)),
+            },
+            '=' if !in_value => in_value = true,
+            ',' => {
+                if !in_value || key.is_empty() {
+                    return Err(format!("Expected <key>=<value> in spec '{}'.", spec));
+                }
+                pairs.push((std::mem::take(&mut key), std::mem::take(&mut value)));
+                in_value = false;
+            }
+            _ => cur.push(c),
+        }
+    }
+    if !in_value || key.is_empty() {
+        return Err(format!("Expected <key>=<value> in spec '{}'.", spec));
+    }
+    pairs.push((key, value));
+    Ok(pairs)
+}
+
+/// Tells if a spec uses the structured `key=value,...` syntax, that is, if
+/// it starts with one of the given keys followed by `=`.
+fn is_structured_spec(spec: &str, keys: &[&str]) -> bool {
+    spec.split_once('=').is_some_and(|(k, _)| keys.contains(&k))
+}
+
+/// Parses a column rename of the form `<colIndex>:<newName>`.
+fn parse_rename(s: &str) -> Option<(usize, String)> {
+    let (idx, name) = s.split_once(':')?;
+    if name.is_empty() {
+        return None;
+    }
+    Some((idx.parse().ok()?, name.to_string()))
+}
+
+const VERTEX_SPEC_KEYS: &[&str] = &[
+    "collection",
+    "file",
+    "output",
+    "type",
+    "separator",
+    "quote-char",
+    "escape",
+    "header",
+    "column",
+    "satellite",
+];
+const EDGE_SPEC_KEYS: &[&str] = &[
+    "file",
+    "from",
+    "to",
+    "rename",
+    "type",
+    "separator",
+    "quote-char",
+    "escape",
+    "header",
+    "column",
+];
+
+/// Parses the per file format settings in a structured spec, returns
+/// `Ok(false)` if `key` is not one of them. The `column` key can be
+/// repeated to give the names of all CSV columns.
+fn parse_format_key(
+    key: &str,
+    value: &str,
+    data_type: &mut Option<DataType>,
+    csv: (
+        &mut Option<String>,
+        &mut Option<char>,
+        &mut Option<EscapeStyle>,
+    ),
+    header: &mut Option<bool>,
+    columns: &mut Option<Vec<String>>,
+) -> Result<bool, String> {
+    let (sep, quo, esc) = csv;
+    match key {
+        "column" if value.is_empty() => return Err("Empty column name.".to_string()),
+        "column" => columns.get_or_insert_with(Vec::new).push(value.to_string()),
+        "type" => *data_type = Some(parse_data_type(value)?),
+        "separator" => *sep = Some(parse_separator(value)?),
+        "quote-char" => *quo = Some(parse_char(value, "quote character")?),
+        "escape" => *esc = Some(parse_escape_style(value)?),
+        "header" => *header = Some(parse_bool(value)?),
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+/// Guesses the data type and the separator from the file extension.
+fn detect_format(file_name: &str) -> (Option<DataType>, Option<String>) {
+    let ext = Path::new(file_name)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase());
+    match ext.as_deref() {
+        Some("csv") => (Some(DataType::Csv), None),
+        Some("tsv") => (Some(DataType::Csv), Some("\t".to_string())),
+        Some("jsonl") | Some("ndjson") => (Some(DataType::Jsonl), None),
+        _ => (None, None),
+    }
+}
+
+/// Fills in the data type and separator of a collection from the file
+/// extension, unless they are set for the collection or given globally.
+fn apply_detected_format(
+    file_name: &str,
+    data_type: &mut Option<DataType>,
+    sep: &mut Option<String>,
+    type_given: bool,
+    sep_given: bool,
+) {
+    let (detected_type, detected_sep) = detect_format(file_name);
+    if data_type.is_none() && !type_given {
+        *data_type = detected_type;
+    }
+    if sep.is_none() && !sep_given {
+        *sep = detected_sep;
+    }
+}
+
+fn parse_vertex_collections(vertices_list: Vec<String>) -> Result<Vec<VertexCollection>, String> {
+    let mut res: Vec<VertexCollection> = vec![];
+    for v in vertices_list {
+        if is_structured_spec(&v, VERTEX_SPEC_KEYS) {
+            // Format: collection=<collname>,file=<filename>[,output=<outputfile>]
+            let mut coll = VertexCollection::default();
+            for (key, value) in parse_spec_pairs(&v)? {
+                if parse_format_key(
+                    &key,
+                    &value,
+                    &mut coll.data_type,
+                    (&mut coll.sep, &mut coll.quo, &mut coll.esc),
+                    &mut coll.header,
+                    &mut coll.columns,
+                )? {
+                    continue;
+                }
+                match key.as_str() {
+                    "collection" => coll.coll_name = value,
+                    "file" => coll.file_name = value,
+                    "output" => coll.output_file = Some(value),
+                    "satellite" => coll.satellite = parse_bool(&value)?,
+                    _ => {
+                        return Err(format!(
+                            "Unknown key '{}' in vertex collection spec '{}'.",
+                            key, v
+                        ))
+                    }
+                }
+            }
+            if coll.coll_name.is_empty() || coll.file_name.is_empty() {
+                return Err(format!(
+                    "Vertex collection spec '{}' needs collection and file.",
+                    v
+                ));
+            }
+            res.push(coll);
+            continue;
+        }
+
+        // Format: <collname>:<filename>[:<outputfile>]
+        let parts: Vec<&str> = v.split(':').collect();
+        if parts.len() < 2 || parts.len() > 3 || parts.iter().any(|p| p.is_empty()) {
+            return Err(format!(
+                "Invalid format for vertex collection spec '{}', use <coll>:<file>[:<output>] or collection=<coll>,file=<file>[,output=<output>].",
+                v
+            ));
+        }
+
+        res.push(VertexCollection {
+            coll_name: parts[0].to_string(),
+            file_name: parts[1].to_string(),
+            output_file: parts.get(2).map(|o| o.to_string()),
+            ..Default::default()
+        });
+    }
+    Ok(res)
+}
+
+fn parse_edge_collections(edges_list: Vec<String>) -> Result<Vec<EdgeCollection>, String> {
+    let mut collections = Vec::new();
+
+    for e in edges_list {
+        if is_structured_spec(&e, EDGE_SPEC_KEYS) {
+            // Format: file=<file>,from=<fromColl>,to=<toColl>[,rename=<colIndex>:<newName> ...]
+            // where from, to and rename can be repeated.
+            let mut coll = EdgeCollection::default();
+            for (key, value) in parse_spec_pairs(&e)? {
+                if parse_format_key(
+                    &key,
+                    &value,
+                    &mut coll.data_type,
+                    (&mut coll.sep, &mut coll.quo, &mut coll.esc),
+                    &mut coll.header,
+                    &mut coll.columns,
+                )? {
+                    continue;
+                }
+                match key.as_str() {
+                    "file" => coll.file_name = value,
+                    "from" if !value.is_empty() => coll.from_vertex_colls.push(value),
+                    "to" if !value.is_empty() => coll.to_vertex_colls.push(value),
+                    "rename" => match parse_rename(&value) {
+                        Some(rename) => coll.column_renames.push(rename),
+                        None => {
+                            return Err(format!(
+                                "Invalid rename '{}' in edge spec '{}', use <colIndex>:<newName>.",
+                                value, e
+                            ))
+                        }
+                    },
+                    _ => return Err(format!("Unknown key '{}' in edge spec '{}'.", key, e)),
+                }
+            }
+            if coll.file_name.is_empty()
+                || coll.from_vertex_colls.is_empty()
+                || coll.to_vertex_colls.is_empty()
+            {
+                return Err(format!("Edge spec '{}' needs file, from and to.", e));
+            }
+            collections.push(coll);
+            continue;
+        }
+
+        // Format: <file>:<fromColls>:<toColls>[:<colIndex>:<newName> ...]
+        // where <fromColls> and <toColls> are comma separated lists.
+        // We'll manually parse up to the third colon, then parse renames.
+        let parts: Vec<&str> = e.split(':').collect();
+        if parts.len() < 3 || parts[0].is_empty() {
+            return Err(format!("Invalid format for edge spec '{}'.", e));
+        }
+
+        let file_name = parts[0].to_string();
+        let coll_list = |s: &str| -> Vec<String> {
+            s.split(',')
+                .filter(|c| !c.is_empty())
+                .map(|c| c.to_string())
+                .collect()
+        };
+        let from_vertex_colls = coll_list(parts[1]);
+        let to_vertex_colls = coll_list(parts[2]);
+        if from_vertex_colls.is_empty() || to_vertex_colls.is_empty() {
+            return Err(format!(
+                "Edge spec '{}' needs from and to vertex collections.",
+                e
+            ));
+        }
+
+        // The rest must be rename specs in pairs: (colIndex, newName)
+        if parts.len().is_multiple_of(2) {
+            return Err(format!(
+                "Edge spec '{}' has an incomplete rename, use the structured syntax for file names with colons.",
+                e
+            ));
+        }
+        let mut renames = Vec::new();
+        for pair in parts[3..].chunks(2) {
+            match parse_rename(&format!("{}:{}", pair[0], pair[1])) {
+                Some(rename) => renames.push(rename),
+                None => {
+                    return Err(format!(
+                        "Invalid rename '{}:{}' in edge spec '{}', use the structured syntax for file names with colons.",
+                        pair[0], pair[1], e
+                    ))
+                }
+            }
+        }
+
+        collections.push(EdgeCollection {
+            file_name,
+            from_vertex_colls,
+            to_vertex_colls,
+            column_renames: renames,
+            ..Default::default()
+        });
+    }
+
+    Ok(collections)
+}
+
+/// What to do with an edge whose `_from` or `_to` cannot be found in the
+/// translation table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum UnresolvedPolicy {
+    Keep,
+    DropEdge,
+    Reject,
+    Error,
+    Default(String),
+}
+
+fn parse_unresolved_policy(s: &str) -> Option<UnresolvedPolicy> {
+    match s {
+        "keep" => Some(UnresolvedPolicy::Keep),
+        "drop-edge" => Some(UnresolvedPolicy::DropEdge),
+        "reject" => Some(UnresolvedPolicy::Reject),
+        "error" => Some(UnresolvedPolicy::Error),
+        _ => s
+            .strip_prefix("default:")
+            .filter(|v| !v.is_empty())
+            .map(|v| UnresolvedPolicy::Default(v.to_string())),
+    }
+}
+
+/// What to do with an edge between vertices with different smart graph
+/// attribute values in a disjoint smart graph.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DisjointPolicy {
+    Reject,
+    Error,
+}
+
+fn parse_disjoint_policy(s: &str) -> Option<DisjointPolicy> {
+    match s {
+        "reject" => Some(DisjointPolicy::Reject),
+        "error" => Some(DisjointPolicy::Error),
+        _ => None,
+    }
+}
+
+/// Options for edge mode which apply to all edge collections.
+#[derive(Clone)]
+struct EdgeOptions {
+    data_type: DataType,
+    csv: CsvDialect,
+    smart_index: i32,
+    // if set, the smart graph attribute value is extracted from the key
+    // with this regular expression, instead of looking it up
+    smart_regex: Option<Regex>,
+    // if set, the smart graph attribute value derived from the key (or the
+    // key itself) is mapped with this table
+    smart_map: Option<Arc<SmartMap>>,
+    // if set, the key prefix is computed with `enterprise_prefix`
+    enterprise: bool,
+    on_unresolved: UnresolvedPolicy,
+    // if set, edges must connect vertices with the same smart graph
+    // attribute value, see `check_disjoint`
+    disjoint: Option<DisjointPolicy>,
+    // renames vertex collections in `_from`/`_to` (old name -> new name)
+    collection_map: HashMap<String, String>,
+    // the attributes which contain the `_from` and `_to` values
+    from_attr: String,
+    to_attr: String,
+    // if false, the CSV input has no header line
+    header: bool,
+    // if not empty, the names of the CSV columns, replacing the header line
+    columns: Vec<String>,
+    output: OutputFormat,
+}
+
+/// Counters collected while transforming one edge file.
+#[derive(Default)]
+struct EdgeStats {
+    edges: u64,
+    dropped: u64,
+    rejected: u64,
+    // number of edges between different smart graph attribute values
+    cross_partition: u64,
+    // number of unresolved endpoints by referenced vertex collection
+    unresolved: BTreeMap<String, u64>,
+    // number of endpoints in collections which are not permitted/declared
+    unknown: BTreeMap<String, u64>,
+}
+
+impl EdgeStats {
+    fn add(&mut self, other: &EdgeStats) {
+        self.edges += other.edges;
+        self.dropped += other.dropped;
+        self.rejected += other.rejected;
+        self.cross_partition += other.cross_partition;
+        for (coll, n) in &other.unresolved {
+            *self.unresolved.entry(coll.clone()).or_insert(0) += n;
+        }
+        for (coll, n) in &other.unknown {
+            *self.unknown.entry(coll.clone()).or_insert(0) += n;
+        }
+    }
+
+    fn report(&self, what: &str) {
+        let per_coll = |counts: &BTreeMap<String, u64>| -> String {
+            counts
+                .iter()
+                .map(|(coll, n)| format!("{}: {}", coll, n))
+                .collect::<Vec<String>>()
+                .join(", ")
+        };
+        let total: u64 = self.unresolved.values().sum();
+        if total > 0 {
+            eprintln!(
+                "Warning: {} unresolved endpoints in {} ({}), {} edges dropped, {} edges rejected.",
+                total,
+                what,
+                per_coll(&self.unresolved),
+                self.dropped,
+                self.rejected
+            );
+        }
+        let total: u64 = self.unknown.values().sum();
+        if total > 0 {
+            eprintln!(
+                "Warning: {} endpoints in {} point to unknown vertex collections ({}).",
+                total,
+                what,
+                per_coll(&self.unknown)
+            );
+        }
+        if self.cross_partition > 0 {
+            eprintln!(
+                "Warning: {} edges in {} connect different smart graph attribute values.",
+                self.cross_partition, what
+            );
+        }
+    }
+}
+
+/// The result of translating a single `_from` or `_to` value.
+struct Endpoint {
+    // the new value, `<coll>/<smart>:<key>` if it could be resolved
+    id: String,
+    // the vertex collection
+    coll: String,
+    // the smart graph attribute value, if known
+    att: Option<String>,
+    // the vertex collection, if the key was not found in the translation
+    unresolved: Option<String>,
+    // if set, the vertex is in a satellite collection and has no smart
+    // graph attribute value
+    satellite: bool,
+}
+
+/// Translates a `_from` or `_to` value into smart graph format. If the
+/// value has no collection prefix, `default_coll` is used. The collection
+/// name is then mapped with `--map-collection`. The smart graph attribute
+/// value is either taken from the prefix of the key (if `smart_index` is
+/// positive) or looked up in the translation table. In an EnterpriseGraph,
+/// it is computed from the key. Values of satellite collections are not
+/// changed.
+fn translate_endpoint(
+    value: &str,
+    default_coll: &str,
+    opts: &EdgeOptions,
+    translation: &Translation,
+) -> Endpoint {
+    let (coll, key) = match value.find('/') {
+        Some(slashpos) => (&value[..slashpos], &value[slashpos + 1..]),
+        None => (default_coll, value),
+    };
+    let coll = opts
+        .collection_map
+        .get(coll)
+        .map(|c| c.as_str())
+        .unwrap_or(coll);
+    if translation.satellites.contains(coll) {
+        return Endpoint {
+            id: format!("{}/{}", coll, key),
+            coll: coll.to_string(),
+            att: None,
+            unresolved: None,
+            satellite: true,
+        };
+    }
+    if let Some(colpos) = key.find(':') {
+        // already transformed
+        return Endpoint {
+            id: format!("{}/{}", coll, key),
+            coll: coll.to_string(),
+            att: Some(key[..colpos].to_string()),
+            unresolved: None,
+            satellite: false,
+        };
+    }
+    let local = match &opts.smart_regex {
+        _ if opts.enterprise => Some(enterprise_prefix(key)),
+        Some(re) => smart_from_regex(re, key),
+        None if opts.smart_index > 0 && key.len() as i32 > opts.smart_index => {
+            Some(key[..opts.smart_index as usize].to_string())
+        }
+        None => None,
+    };
+    // A locally derived value is mapped with the fallback, a whole key is
+    // only mapped if it is in the table and looked up otherwise.
+    let local = match (&opts.smart_map, local) {
+        (Some(map), Some(raw)) => map.lookup(&raw),
+        (Some(map), None) => map.values.get(key).cloned(),
+        (None, local) => local,
+    };
+    if let Some(att) = local {
+        return Endpoint {
+            id: format!("{}/{}:{}", coll, att, key),
+            coll: coll.to_string(),
+            att: Some(att),
+            unresolved: None,
+            satellite: false,
+        };
+    }
+    let full_key = format!("{}/{}", coll, key);
+    if let Some(att) = translation.smart_attribute(&full_key) {
+        return Endpoint {
+            id: format!("{}/{}:{}", coll, att, key),
+            coll: coll.to_string(),
+            att: Some(att.to_string()),
+            unresolved: None,
+            satellite: false,
+        };
+    }
+    match &opts.on_unresolved {
+        UnresolvedPolicy::Default(att) => Endpoint {
+            id: format!("{}/{}:{}", coll, att, key),
+            coll: coll.to_string(),
+            att: Some(att.clone()),
+            unresolved: Some(coll.to_string()),
+            satellite: false,
+        },
+        _ => Endpoint {
+            id: full_key,
+            coll: coll.to_string(),
+            att: None,
+            unresolved: Some(coll.to_string()),
+            satellite: false,
+        },
+    }
+}
+
+/// Builds the smart edge key `<fromSmart>:<key>:<toSmart>`, the smart
+/// value of a satellite endpoint is left empty, like in `<fromSmart>:<key>:`.
+/// Returns `None` if the key is already smart, if an endpoint has no smart
+/// value or if both endpoints are satellites.
+fn smart_edge_key(from: &Endpoint, to: &Endpoint, key: &str) -> Option<String> {
+    if key.contains(':') || (from.satellite && to.satellite) {
+        return None;
     }
-    0
+    let prefix = |ep: &Endpoint| {
+        if ep.satellite {
+            Some(String::new())
+        } else {
+            ep.att.clone()
+        }
+    };
+    Some(format!("{}:{}:{}", prefix(from)?, key, prefix(to)?))
 }
 
-// ------------------------------------------
-// do_edges: rewrite edges for smartification
-// ------------------------------------------
+/// How many endpoints pointing to an unknown vertex collection are reported
+/// per edge file and collection, all of them are counted in `EdgeStats`.
+const MAX_UNKNOWN_REPORTS: u64 = 10;
 
-fn parse_vertex_collections(vertices_list: Vec<String>) -> Vec<VertexCollection> {
-    let mut res: Vec<VertexCollection> = vec![];
-    for v in vertices_list {
-        // Format: <collname>:<filename>
-        // We'll manually parse up to the third colon, then parse renames.
-        let parts: Vec<&str> = v.split(':').collect();
-        if parts.len() < 2 {
-            eprintln!(
-                "Invalid format for vertex collection spec '{}'. Skipping.",
-                v
-            );
-            continue;
-        }
+/// Checks that the collection of an endpoint is permitted for this side of
+/// the edge collection and was declared with `--vertices` (if any vertex
+/// collections were given at all). Edges pointing elsewhere are reported.
+fn check_endpoint_collection(
+    ep: &Endpoint,
+    field: &str,
+    permitted: &[String],
+    translation: &Translation,
+    file_name: &str,
+    line_nr: u64,
+    stats: &mut EdgeStats,
+) {
+    let declared = translation.collections.is_empty() || translation.collections.contains(&ep.coll);
+    if declared && permitted.contains(&ep.coll) {
+        return;
+    }
+    let count = stats.unknown.entry(ep.coll.clone()).or_insert(0);
+    *count += 1;
+    if *count <= MAX_UNKNOWN_REPORTS {
+        eprintln!(
+            "{} value {} in {} line {} points to unknown vertex collection {}",
+            field, ep.id, file_name, line_nr, ep.coll
+        );
+    }
+    if *count == MAX_UNKNOWN_REPORTS {
+        eprintln!(
+            "Not reporting further endpoints in {} pointing to vertex collection {}",
+            file_name, ep.coll
+        );
+    }
+}
 
-        res.push(VertexCollection {
-            coll_name: parts[0].to_string(),
-            file_name: parts[1].to_string(),
-        });
+/// Opens the file for rejected edges, if the policy asks for it.
+fn open_reject_file(
+    file_name: &str,
+    policy: &UnresolvedPolicy,
+) -> Result<Option<BufWriter<File>>, i32> {
+    if *policy != UnresolvedPolicy::Reject {
+        return Ok(None);
     }
-    res
+    open_edge_side_file(file_name, ".rejected")
 }
 
-fn parse_edge_collections(edges_list: Vec<String>) -> Vec<EdgeCollection> {
-    let mut collections = Vec::new();
+/// Opens the file for cross-partition edges, if `--disjoint` asks for it.
+fn open_cross_partition_file(
+    file_name: &str,
+    policy: Option<DisjointPolicy>,
+) -> Result<Option<BufWriter<File>>, i32> {
+    if policy != Some(DisjointPolicy::Reject) {
+        return Ok(None);
+    }
+    open_edge_side_file(file_name, ".crosspartition")
+}
 
-    for e in edges_list {
-        // Format: <file>:<fromColl>:<toColl>[:<colIndex>:<newName> ...]
-        // We'll manually parse up to the third colon, then parse renames.
-        let parts: Vec<&str> = e.split(':').collect();
-        if parts.len() < 3 {
-            eprintln!("Invalid format for edge spec '{}'. Skipping.", e);
-            continue;
+/// Creates a file next to an edge file for edges which are left out.
+fn open_edge_side_file(file_name: &str, suffix: &str) -> Result<Option<BufWriter<File>>, i32> {
+    let reject_name = file_name.to_string() + suffix;
+    match File::create(&reject_name) {
+        Ok(f) => Ok(Some(BufWriter::new(f))),
+        Err(e) => {
+            eprintln!("Cannot create reject file {}: {}", reject_name, e);
+            Err(2)
         }
+    }
+}
 
-        let file_name = parts[0].to_string();
-        let from_vertex_coll = parts[1].to_string();
-        let to_vertex_coll = parts[2].to_string();
-
-        // The rest might be rename specs in pairs: (colIndex, newName)
-        let mut renames = Vec::new();
-        let mut idx = 3;
-        while idx + 1 < parts.len() {
-            let col_index_str = &parts[idx];
-            let new_name = &parts[idx + 1];
-            if let Ok(col_index) = col_index_str.parse::<usize>() {
-                renames.push((col_index, new_name.to_string()));
+/// Applies the unresolved policy to an edge with at least one unresolved
+/// endpoint. Returns `Ok(true)` if the edge should still be written and
+/// `Err(code)` if the transformation has to be aborted.
+fn handle_unresolved(
+    endpoints: &[&Endpoint],
+    line: &str,
+    line_nr: u64,
+    file_name: &str,
+    policy: &UnresolvedPolicy,
+    rejects: Option<&mut BufWriter<File>>,
+    stats: &mut EdgeStats,
+) -> Result<bool, i32> {
+    let mut any = false;
+    for ep in endpoints {
+        if let Some(coll) = &ep.unresolved {
+            *stats.unresolved.entry(coll.clone()).or_insert(0) += 1;
+            any = true;
+        }
+    }
+    if !any {
+        return Ok(true);
+    }
+    match policy {
+        UnresolvedPolicy::Keep | UnresolvedPolicy::Default(_) => Ok(true),
+        UnresolvedPolicy::DropEdge => {
+            stats.dropped += 1;
+            Ok(false)
+        }
+        UnresolvedPolicy::Reject => {
+            if let Some(w) = rejects {
+                writeln!(w, "{}", line).unwrap();
             }
-            idx += 2;
+            stats.rejected += 1;
+            Ok(false)
+        }
+        UnresolvedPolicy::Error => {
+            for ep in endpoints.iter().filter(|ep| ep.unresolved.is_some()) {
+                eprintln!(
+                    "Unresolved endpoint {} in {} line {}",
+                    ep.id, file_name, line_nr
+                );
+            }
+            Err(6)
         }
-
-        collections.push(EdgeCollection {
-            file_name,
-            from_vertex_coll,
-            to_vertex_coll,
-            column_renames: renames,
-        });
     }
+}
 
-    collections
+/// Checks that an edge connects two vertices with the same smart graph
+/// attribute value, as needed for a disjoint smart graph. Edges with a
+/// satellite or an unresolved endpoint are not checked. Returns `Ok(true)`
+/// if the edge should be written and `Err(code)` if the transformation has
+/// to be aborted.
+#[allow(clippy::too_many_arguments)]
+fn check_disjoint(
+    from: &Endpoint,
+    to: &Endpoint,
+    line: &str,
+    line_nr: u64,
+    file_name: &str,
+    policy: Option<DisjointPolicy>,
+    crossing: Option<&mut BufWriter<File>>,
+    stats: &mut EdgeStats,
+) -> Result<bool, i32> {
+    let Some(policy) = policy else {
+        return Ok(true);
+    };
+    match (&from.att, &to.att) {
+        (Some(from_att), Some(to_att)) if from_att != to_att => {}
+        _ => return Ok(true),
+    }
+    stats.cross_partition += 1;
+    match policy {
+        DisjointPolicy::Reject => {
+            if let Some(w) = crossing {
+                writeln!(w, "{}", line).unwrap();
+            }
+            Ok(false)
+        }
+        DisjointPolicy::Error => {
+            eprintln!(
+                "Edge from {} to {} in {} line {} connects different smart graph attribute values",
+                from.id, to.id, file_name, line_nr
+            );
+            Err(7)
+        }
+    }
 }
 
 /// Transforms edges in CSV.
 fn transform_edges_csv(
     edge_coll: &EdgeCollection,
-    sep: char,
-    quo: char,
-    smart_index: i32,
+    opts: &EdgeOptions,
     translation: &Translation,
+    stats: &mut EdgeStats,
 ) -> i32 {
     println!(
         "{:.3} Transforming edges in {}",
         elapsed(),
         edge_coll.file_name
     );
+    let csv = &opts.csv;
 
     // read original
     let in_path = Path::new(&edge_coll.file_name);
@@ -908,7 +4672,7 @@ fn transform_edges_csv(
         }
     };
     let reader = BufReader::new(input);
-    let output = match File::create(&out_path) {
+    let output = match File::create(out_path) {
         Ok(f) => f,
         Err(e) => {
             eprintln!(
@@ -918,17 +4682,39 @@ fn transform_edges_csv(
             return 2;
         }
     };
-    let mut writer = BufWriter::new(output);
+    let mut writer = RecordWriter::new(
+        BufWriter::new(output),
+        DataType::Csv,
+        csv,
+        &opts.output,
+        &["_key", &opts.from_attr, &opts.to_attr],
+    );
+    let mut rejects = match open_reject_file(&edge_coll.file_name, &opts.on_unresolved) {
+        Ok(r) => r,
+        Err(code) => return code,
+    };
+    let mut crossing = match open_cross_partition_file(&edge_coll.file_name, opts.disjoint) {
+        Ok(c) => c,
+        Err(code) => return code,
+    };
 
     let mut lines = reader.lines();
-    let Some(Ok(header_line)) = lines.next() else {
+    let Some((mut col_headers, first_line)) =
+        read_csv_header(&mut lines, opts.header, &opts.columns, csv)
+    else {
         eprintln!("Empty or invalid edge file {}", &edge_coll.file_name);
         return 3;
     };
-    let mut col_headers = split(&header_line, sep, quo)
-        .into_iter()
-        .map(|s| unquote(&s, quo))
-        .collect::<Vec<String>>();
+    if opts.header {
+        let header_line = col_headers
+            .iter()
+            .map(|c| quote_string(c, csv))
+            .collect::<Vec<String>>()
+            .join(&csv.sep);
+        for w in rejects.iter_mut().chain(crossing.iter_mut()) {
+            writeln!(w, "{}", header_line).unwrap();
+        }
+    }
 
     // rename columns if needed
     for (col_idx, new_name) in &edge_coll.column_renames {
@@ -938,155 +4724,564 @@ fn transform_edges_csv(
     }
 
     // write out the new header
-    if !col_headers.is_empty() {
-        write!(writer, "{}", quote_string(&col_headers[0], quo)).unwrap();
-    }
-    for c in &col_headers[1..] {
-        write!(writer, "{}{}", sep, quote_string(c, quo)).unwrap();
-    }
-    writeln!(writer).unwrap();
+    writer.write_header(&col_headers);
 
     // try to find _from, _to, _key
-    let from_pos = find_col_pos(&col_headers, "_from");
-    let to_pos = find_col_pos(&col_headers, "_to");
+    let from_pos = find_col_pos(&col_headers, &opts.from_attr);
+    let to_pos = find_col_pos(&col_headers, &opts.to_attr);
     let key_pos = find_col_pos(&col_headers, "_key");
 
     if from_pos < 0 || to_pos < 0 {
         eprintln!(
-            "Did not find _from or _to field in {}, skipping transformations.",
-            edge_coll.file_name
+            "Did not find {} or {} field in {}, skipping transformations.",
+            opts.from_attr, opts.to_attr, edge_coll.file_name
         );
         return 4;
     }
 
-    let mut count = 0usize;
-    for line_result in lines {
+    let mut line_nr: u64 = if opts.header { 1 } else { 0 };
+    for line_result in first_line.map(Ok).into_iter().chain(lines) {
+        line_nr += 1;
         let Ok(line_str) = line_result else {
             continue;
         };
-        let mut parts = split(&line_str, sep, quo);
+        let mut parts = split(&line_str, csv);
         while parts.len() < col_headers.len() {
             parts.push(String::new());
         }
 
-        // We'll define an inline closure to fix either _from or _to
-        // in a simplified manner. The original code tries to do a
-        // "translation" from a big table, or do prefix from smart_index.
-        // We'll do a partial approach here.
-
-        let fix_vertex = |pos: usize, default_coll: &str, parts: &mut [String]| -> String {
-            let unquoted = unquote(&parts[pos], quo);
-            if unquoted.contains('/') {
-                // see if we already have a colon
-                if unquoted.find(':').is_some() {
-                    // already transformed
-                    parts[pos] = quote_string(&unquoted, quo);
-                    let slashpos = unquoted.find('/').unwrap_or(0);
-                    let after_slash = &unquoted[slashpos + 1..];
-                    if let Some(colpos) = after_slash.find(':') {
-                        // get the part between slash and colon
-                        return after_slash[..colpos].to_string();
-                    }
-                    return "".to_string();
-                } else {
-                    // we do default approach
-                    let slashpos = unquoted.find('/').unwrap();
-                    let key_after_slash = &unquoted[slashpos + 1..];
-                    if smart_index > 0 && key_after_slash.len() as i32 > smart_index {
-                        let att = &key_after_slash[..(smart_index as usize)];
-                        let new_value =
-                            format!("{}/{}:{}", &unquoted[..slashpos], att, &key_after_slash);
-                        parts[pos] = quote_string(&new_value, quo);
-                        return att.to_string();
-                    } else {
-                        // do the translation approach
-                        let mut found_smart = "".to_string();
-                        let full_key = unquoted;
-                        if let Some(&att_idx) = translation.key_tab.get(&full_key) {
-                            let att = &translation.smart_attributes[att_idx as usize];
-                            let after_s = &full_key[slashpos + 1..];
-                            let new_val = format!("{}/{}:{}", &full_key[..slashpos], att, after_s);
-                            parts[pos] = quote_string(&new_val, quo);
-                            found_smart = att.to_string();
-                        } else {
-                            // not found => keep as is
-                            parts[pos] = quote_string(&full_key, quo);
+        // We'll define an inline closure to fix either _from or _to,
+        // the actual work is done in `translate_endpoint`.
+        let fix_vertex = |pos: usize, default_coll: &str, parts: &mut [String]| -> Endpoint {
+            let unquoted = unquote(&parts[pos], csv);
+            let ep = translate_endpoint(&unquoted, default_coll, opts, translation);
+            parts[pos] = quote_string(&ep.id, csv);
+            ep
+        };
+
+        let from = fix_vertex(
+            from_pos as usize,
+            &edge_coll.from_vertex_colls[0],
+            &mut parts,
+        );
+        let to = fix_vertex(to_pos as usize, &edge_coll.to_vertex_colls[0], &mut parts);
+        for (ep, field, permitted) in [
+            (&from, &opts.from_attr, &edge_coll.from_vertex_colls),
+            (&to, &opts.to_attr, &edge_coll.to_vertex_colls),
+        ] {
+            check_endpoint_collection(
+                ep,
+                field,
+                permitted,
+                translation,
+                &edge_coll.file_name,
+                line_nr,
+                stats,
+            );
+        }
+        let keep = handle_unresolved(
+            &[&from, &to],
+            &line_str,
+            line_nr,
+            &edge_coll.file_name,
+            &opts.on_unresolved,
+            rejects.as_mut(),
+            stats,
+        )
+        .and_then(|keep| {
+            if !keep {
+                return Ok(false);
+            }
+            check_disjoint(
+                &from,
+                &to,
+                &line_str,
+                line_nr,
+                &edge_coll.file_name,
+                opts.disjoint,
+                crossing.as_mut(),
+                stats,
+            )
+        });
+        match keep {
+            Ok(true) => {}
+            Ok(false) => continue,
+            Err(code) => {
+                drop(writer);
+                std::fs::remove_file(out_path).ok();
+                return code;
+            }
+        }
+
+        // If _key is present and from/to are valid, then we might do a triple prefix
+        if key_pos >= 0 {
+            let kpos = key_pos as usize;
+            if let Some(new_key) = smart_edge_key(&from, &to, &unquote(&parts[kpos], csv)) {
+                parts[kpos] = quote_string(&new_key, csv);
+            }
+        }
+
+        // rewrite the line
+        writer.write_fields(parts);
+
+        stats.edges += 1;
+        if stats.edges.is_multiple_of(1_000_000) {
+            println!(
+                "{:.3} Have transformed {} edges in {} ...",
+                elapsed(),
+                stats.edges,
+                edge_coll.file_name
+            );
+        }
+    }
+
+    // flush/close
+    if let Err(e) = writer.finish() {
+        eprintln!(
+            "Error flushing edge temp file {}.out: {}",
+            edge_coll.file_name, e
+        );
+        return 5;
+    }
+    if let Some(Err(e)) = rejects.as_mut().map(|w| w.flush()) {
+        eprintln!(
+            "Error flushing reject file {}.rejected: {}",
+            edge_coll.file_name, e
+        );
+        return 5;
+    }
+    if let Some(Err(e)) = crossing.as_mut().map(|w| w.flush()) {
+        eprintln!(
+            "Error flushing cross-partition file {}.crosspartition: {}",
+            edge_coll.file_name, e
+        );
+        return 5;
+    }
+
+    replace_edge_file(&edge_coll.file_name, out_path, opts);
+
+    println!(
+        "{:.3} Done transforming edges in {}",
+        elapsed(),
+        edge_coll.file_name
+    );
+    0
+}
+
+/// A string attribute found by `scan_json_strings`: the byte range of the
+/// JSON string in the line (including the quotes) and its value.
+type ScannedString<'a> = (Range<usize>, Cow<'a, str>);
+
+/// Scans the top-level attributes of the JSON object in `line` for the
+/// string values of the attributes in `names`, without parsing the rest.
+/// Returns `None` if the line is no object, if one of the attributes is
+/// not a string or if something unexpected is found, in which case the
+/// line has to be parsed completely.
+fn scan_json_strings<'a>(line: &'a str, names: &[&str]) -> Option<Vec<Option<ScannedString<'a>>>> {
+    let bytes = line.as_bytes();
+    let mut found = vec![None; names.len()];
+    let mut pos = skip_ws(bytes, 0);
+    if bytes.get(pos) != Some(&b'{') {
+        return None;
+    }
+    pos = skip_ws(bytes, pos + 1);
+    if bytes.get(pos) != Some(&b'}') {
+        loop {
+            let key_end = scan_json_string(bytes, pos)?;
+            let key = json_str_value(line, pos..key_end)?;
+            pos = skip_ws(bytes, key_end);
+            if bytes.get(pos) != Some(&b':') {
+                return None;
+            }
+            pos = skip_ws(bytes, pos + 1);
+            let value_end = match names.iter().position(|n| *n == key) {
+                Some(i) => {
+                    let end = scan_json_string(bytes, pos)?;
+                    found[i] = Some((pos..end, json_str_value(line, pos..end)?));
+                    end
+                }
+                None => skip_json_value(bytes, pos)?,
+            };
+            pos = skip_ws(bytes, value_end);
+            match bytes.get(pos) {
+                Some(b',') => pos = skip_ws(bytes, pos + 1),
+                Some(b'}') => break,
+                _ => return None,
+            }
+        }
+    }
+    (skip_ws(bytes, pos + 1) == bytes.len()).then_some(found)
+}
+
+fn skip_ws(bytes: &[u8], mut pos: usize) -> usize {
+    while bytes.get(pos).is_some_and(|b| b.is_ascii_whitespace()) {
+        pos += 1;
+    }
+    pos
+}
+
+/// Returns the position after the JSON string which starts at `pos`.
+fn scan_json_string(bytes: &[u8], pos: usize) -> Option<usize> {
+    if bytes.get(pos) != Some(&b'"') {
+        return None;
+    }
+    let mut i = pos + 1;
+    loop {
+        match bytes.get(i)? {
+            b'"' => return Some(i + 1),
+            b'\\' => match bytes.get(i + 1)? {
+                b'"' | b'\\' | b'/' | b'b' | b'f' | b'n' | b'r' | b't' => i += 2,
+                b'u' => {
+                    // a high surrogate must be followed by a low one
+                    match json_hex_escape(bytes, i)? {
+                        0xD800..=0xDBFF => {
+                            if !matches!(json_hex_escape(bytes, i + 6)?, 0xDC00..=0xDFFF) {
+                                return None;
+                            }
+                            i += 12;
                         }
-                        return found_smart;
+                        0xDC00..=0xDFFF => return None,
+                        _ => i += 6,
                     }
                 }
-            } else {
-                // doesn't contain '/', so we do the default
-                let new_value = format!("{}/{}", default_coll, unquoted);
-                // now do we do the colon?
-                if smart_index > 0 && unquoted.len() as i32 > smart_index {
-                    let att = &unquoted[..(smart_index as usize)];
-                    let final_val = format!("{}/{}:{}", default_coll, att, &unquoted);
-                    parts[pos] = quote_string(&final_val, quo);
-                    return att.to_string();
-                } else {
-                    // see if it is in translation
-                    let full_key = format!("{}/{}", default_coll, unquoted);
-                    if let Some(&att_idx) = translation.key_tab.get(&full_key) {
-                        let att = &translation.smart_attributes[att_idx as usize];
-                        let just_key = unquoted;
-                        let final_val = format!("{}/{}:{}", default_coll, att, just_key);
-                        parts[pos] = quote_string(&final_val, quo);
-                        return att.to_string();
-                    } else {
-                        parts[pos] = quote_string(&new_value, quo);
-                        return "".to_string();
+                _ => return None,
+            },
+            b if *b < 0x20 => return None,
+            _ => i += 1,
+        }
+    }
+}
+
+/// Returns the code unit of the `\uXXXX` escape at `pos`.
+fn json_hex_escape(bytes: &[u8], pos: usize) -> Option<u16> {
+    if bytes.get(pos..pos + 2)? != b"\\u" {
+        return None;
+    }
+    let hex = std::str::from_utf8(bytes.get(pos + 2..pos + 6)?).ok()?;
+    if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    u16::from_str_radix(hex, 16).ok()
+}
+
+/// Returns the value of the JSON string at `range` in `line`, which is
+/// borrowed from the line unless it contains escape sequences.
+fn json_str_value(line: &str, range: Range<usize>) -> Option<Cow<'_, str>> {
+    let inner = &line[range.start + 1..range.end - 1];
+    if inner.contains('\\') {
+        serde_json::from_str::<String>(&line[range])
+            .ok()
+            .map(Cow::Owned)
+    } else {
+        Some(Cow::Borrowed(inner))
+    }
+}
+
+/// Maximal nesting depth of objects and arrays in a line, serde_json
+/// rejects deeper lines.
+const MAX_JSON_DEPTH: usize = 127;
+
+/// Returns the position after the JSON value which starts at `pos`. The
+/// value is checked like serde_json does, such that the lines which are
+/// not parsed completely are the same as the ones which are.
+fn skip_json_value(bytes: &[u8], pos: usize) -> Option<usize> {
+    // the value is inside the object of the line
+    skip_json_nested(bytes, pos, 1)
+}
+
+fn skip_json_nested(bytes: &[u8], pos: usize, depth: usize) -> Option<usize> {
+    match bytes.get(pos)? {
+        b'"' => scan_json_string(bytes, pos),
+        open @ (b'{' | b'[') => {
+            if depth >= MAX_JSON_DEPTH {
+                return None;
+            }
+            let close = if *open == b'{' { b'}' } else { b']' };
+            let mut i = skip_ws(bytes, pos + 1);
+            if bytes.get(i) == Some(&close) {
+                return Some(i + 1);
+            }
+            loop {
+                if close == b'}' {
+                    i = skip_ws(bytes, scan_json_string(bytes, i)?);
+                    if bytes.get(i) != Some(&b':') {
+                        return None;
                     }
+                    i = skip_ws(bytes, i + 1);
+                }
+                i = skip_ws(bytes, skip_json_nested(bytes, i, depth + 1)?);
+                match bytes.get(i)? {
+                    b',' => i = skip_ws(bytes, i + 1),
+                    b if *b == close => return Some(i + 1),
+                    _ => return None,
                 }
             }
+        }
+        b't' => bytes[pos..].starts_with(b"true").then_some(pos + 4),
+        b'f' => bytes[pos..].starts_with(b"false").then_some(pos + 5),
+        b'n' => bytes[pos..].starts_with(b"null").then_some(pos + 4),
+        _ => skip_json_number(bytes, pos),
+    }
+}
+
+/// Returns the position after the JSON number which starts at `pos`, the
+/// number must be of the form `-?(0|[1-9][0-9]*)(.[0-9]+)?([eE][+-]?[0-9]+)?`.
+fn skip_json_number(bytes: &[u8], pos: usize) -> Option<usize> {
+    let digits = |i: usize| {
+        let n = bytes[i..].iter().take_while(|b| b.is_ascii_digit()).count();
+        (n > 0).then_some(i + n)
+    };
+    let mut i = pos;
+    if bytes.get(i) == Some(&b'-') {
+        i += 1;
+    }
+    i = if bytes.get(i) == Some(&b'0') {
+        i + 1
+    } else {
+        digits(i)?
+    };
+    if bytes.get(i) == Some(&b'.') {
+        i = digits(i + 1)?;
+    }
+    if matches!(bytes.get(i), Some(b'e' | b'E')) {
+        i += 1;
+        if matches!(bytes.get(i), Some(b'+' | b'-')) {
+            i += 1;
+        }
+        i = digits(i)?;
+    }
+    Some(i)
+}
+
+/// Replaces the given byte ranges of `line`, which must not overlap.
+fn splice(line: &str, mut replacements: Vec<(Range<usize>, String)>) -> String {
+    replacements.sort_by_key(|(range, _)| range.start);
+    let mut res = String::with_capacity(line.len() + 64);
+    let mut pos = 0;
+    for (range, new) in replacements {
+        res.push_str(&line[pos..range.start]);
+        res.push_str(&new);
+        pos = range.end;
+    }
+    res.push_str(&line[pos..]);
+    res
+}
+
+/// Transform edges in JSONL in a similar manner. Unless the edges are
+/// converted to CSV, each line is only scanned for `_from`, `_to` and
+/// `_key`, these are replaced and the rest of the line is copied as it
+/// is. Lines which cannot be scanned like this are parsed completely.
+fn transform_edges_jsonl(
+    edge_coll: &EdgeCollection,
+    opts: &EdgeOptions,
+    translation: &Translation,
+    stats: &mut EdgeStats,
+) -> i32 {
+    println!(
+        "{:.3} Transforming JSON edges in {}",
+        elapsed(),
+        edge_coll.file_name
+    );
+    let in_path = Path::new(&edge_coll.file_name);
+    let edge_file_name_out = edge_coll.file_name.clone() + ".out";
+    let out_path = Path::new(&edge_file_name_out);
+    let input = match File::open(in_path) {
+        Ok(f) => f,
+        Err(e) => {
+            eprintln!("Cannot open edge file {}: {}", &edge_coll.file_name, e);
+            return 1;
+        }
+    };
+    let reader = BufReader::new(input);
+    let output = match File::create(out_path) {
+        Ok(f) => f,
+        Err(e) => {
+            eprintln!(
+                "Cannot create temp edge file {}.out: {}",
+                &edge_coll.file_name, e
+            );
+            return 2;
+        }
+    };
+    let mut writer = RecordWriter::new(
+        BufWriter::new(output),
+        DataType::Jsonl,
+        &opts.csv,
+        &opts.output,
+        &[],
+    );
+    let mut rejects = match open_reject_file(&edge_coll.file_name, &opts.on_unresolved) {
+        Ok(r) => r,
+        Err(code) => return code,
+    };
+    let mut crossing = match open_cross_partition_file(&edge_coll.file_name, opts.disjoint) {
+        Ok(c) => c,
+        Err(code) => return code,
+    };
+
+    let fields = [opts.from_attr.as_str(), opts.to_attr.as_str(), "_key"];
+    let paths = fields.map(parse_path);
+    let names: Vec<&str> = paths.iter().map(|p| p[0].as_str()).collect();
+    // nested `_from` and `_to` attributes are not found by the scan
+    let fast_path = !writer.converts() && paths.iter().all(|p| p.len() == 1);
+    let mut line_nr = 0u64;
+    for line_result in reader.lines() {
+        line_nr += 1;
+        let Ok(line_str) = line_result else { continue };
+        let scanned = if fast_path {
+            scan_json_strings(&line_str, &names)
+        } else {
+            None
         };
+        let mut obj = None;
+        if scanned.is_none() {
+            let parsed: Value = match serde_json::from_str(&line_str) {
+                Ok(v) => v,
+                Err(e) => {
+                    eprintln!(
+                        "JSON parse error in file {}, line {}: {}",
+                        edge_coll.file_name, line_nr, e
+                    );
+                    continue;
+                }
+            };
 
-        let from_attr = fix_vertex(from_pos as usize, &edge_coll.from_vertex_coll, &mut parts);
-        let to_attr = fix_vertex(to_pos as usize, &edge_coll.to_vertex_coll, &mut parts);
+            // We expect an object
+            match parsed {
+                Value::Object(m) => obj = Some(m),
+                _ => {
+                    eprintln!(
+                        "Non-object line in JSON edges file {}, line {}. Skipping.",
+                        edge_coll.file_name, line_nr
+                    );
+                    continue;
+                }
+            }
+        }
+        let value = |i: usize| -> Option<Cow<str>> {
+            match (&scanned, &obj) {
+                (Some(found), _) => found[i].as_ref().map(|(_, v)| Cow::Borrowed(v.as_ref())),
+                (None, Some(obj)) => json_string(get_path(obj, &paths[i]), fields[i], i < 2),
+                (None, None) => None,
+            }
+        };
 
-        // If _key is present and from/to are valid, then we might do a triple prefix
-        if key_pos >= 0 && !from_attr.is_empty() && !to_attr.is_empty() {
-            let kpos = key_pos as usize;
-            let unquoted_key = unquote(&parts[kpos], quo);
-            if !unquoted_key.contains(':') {
-                let new_key = format!("{}:{}:{}", from_attr, unquoted_key, to_attr);
-                parts[kpos] = quote_string(&new_key, quo);
+        // fix from/to
+        let from = value(0)
+            .map(|v| translate_endpoint(&v, &edge_coll.from_vertex_colls[0], opts, translation));
+        let to = value(1)
+            .map(|v| translate_endpoint(&v, &edge_coll.to_vertex_colls[0], opts, translation));
+        let key = value(2);
+        for (ep, field, permitted) in [
+            (&from, &opts.from_attr, &edge_coll.from_vertex_colls),
+            (&to, &opts.to_attr, &edge_coll.to_vertex_colls),
+        ] {
+            if let Some(ep) = ep {
+                check_endpoint_collection(
+                    ep,
+                    field,
+                    permitted,
+                    translation,
+                    &edge_coll.file_name,
+                    line_nr,
+                    stats,
+                );
+            }
+        }
+        let endpoints: Vec<&Endpoint> = from.iter().chain(to.iter()).collect();
+        let keep = handle_unresolved(
+            &endpoints,
+            &line_str,
+            line_nr,
+            &edge_coll.file_name,
+            &opts.on_unresolved,
+            rejects.as_mut(),
+            stats,
+        )
+        .and_then(|keep| match (keep, &from, &to) {
+            (true, Some(from), Some(to)) => check_disjoint(
+                from,
+                to,
+                &line_str,
+                line_nr,
+                &edge_coll.file_name,
+                opts.disjoint,
+                crossing.as_mut(),
+                stats,
+            ),
+            _ => Ok(keep),
+        });
+        match keep {
+            Ok(true) => {}
+            Ok(false) => continue,
+            Err(code) => {
+                drop(writer);
+                std::fs::remove_file(out_path).ok();
+                return code;
             }
         }
 
-        // rewrite the line
-        if !parts.is_empty() {
-            write!(writer, "{}", parts[0]).unwrap();
-        }
-        for i in 1..parts.len() {
-            write!(writer, "{}{}", sep, parts[i]).unwrap();
+        let new_key = match (&from, &to, key) {
+            (Some(from), Some(to), Some(k)) => smart_edge_key(from, to, &k),
+            _ => None,
+        };
+
+        // `_from`, `_to` and `_key` are rewritten in place, such that all
+        // other attributes keep their order and their exact values
+        let new_values = [from.map(|ep| ep.id), to.map(|ep| ep.id), new_key];
+        match (scanned, obj) {
+            (Some(found), _) => {
+                let replacements = found
+                    .into_iter()
+                    .zip(new_values)
+                    .filter_map(|(f, new)| Some((f?.0, Value::String(new?).to_string())))
+                    .collect();
+                writer.write_line(&splice(&line_str, replacements));
+            }
+            (None, Some(mut obj)) => {
+                for (path, new) in paths.iter().zip(new_values) {
+                    if let (Some(v), Some(new)) = (get_path_mut(&mut obj, path), new) {
+                        *v = Value::String(new);
+                    }
+                }
+                writer.write_object(obj);
+            }
+            (None, None) => {}
         }
-        writeln!(writer).unwrap();
 
-        count += 1;
-        if count % 1_000_000 == 0 {
+        stats.edges += 1;
+        if stats.edges.is_multiple_of(1_000_000) {
             println!(
                 "{:.3} Have transformed {} edges in {} ...",
                 elapsed(),
-                count,
+                stats.edges,
                 edge_coll.file_name
             );
         }
     }
 
-    // flush/close
-    if let Err(e) = writer.flush() {
+    if let Err(e) = writer.finish() {
         eprintln!(
             "Error flushing edge temp file {}.out: {}",
             edge_coll.file_name, e
         );
         return 5;
     }
-
-    // remove old file, rename new file -> old
-    std::fs::remove_file(&edge_coll.file_name).ok();
-    std::fs::rename(&out_path, &in_path).ok();
-
+    if let Some(Err(e)) = rejects.as_mut().map(|w| w.flush()) {
+        eprintln!(
+            "Error flushing reject file {}.rejected: {}",
+            edge_coll.file_name, e
+        );
+        return 5;
+    }
+    if let Some(Err(e)) = crossing.as_mut().map(|w| w.flush()) {
+        eprintln!(
+            "Error flushing cross-partition file {}.crosspartition: {}",
+            edge_coll.file_name, e
+        );
+        return 5;
+    }
+    replace_edge_file(&edge_coll.file_name, out_path, opts);
     println!(
         "{:.3} Done transforming edges in {}",
         elapsed(),
@@ -1095,246 +5290,593 @@ fn transform_edges_csv(
     0
 }
 
-/// Transform edges in JSONL in a similar manner
-fn transform_edges_jsonl(
-    edge_coll: &EdgeCollection,
-    smart_index: i32,
+/// Moves the transformed edges into place: they replace the input file,
+/// unless they were converted to another data type, in which case they are
+/// written next to it with the extension of the new data type.
+fn replace_edge_file(file_name: &str, out_path: &Path, opts: &EdgeOptions) {
+    match opts.output.data_type {
+        Some(data_type) if data_type != opts.data_type => {
+            let converted = converted_file_name(file_name, data_type);
+            std::fs::rename(out_path, &converted).ok();
+            println!("{:.3} Wrote converted edges to {}", elapsed(), converted);
+        }
+        _ => {
+            // remove old file, rename new file -> old
+            std::fs::remove_file(file_name).ok();
+            std::fs::rename(out_path, file_name).ok();
+        }
+    }
+}
+
+/// Returns the value of a string attribute of a parsed JSON object,
+/// `None` if it is missing or not a string, the latter is reported if
+/// `warn` is set.
+fn json_string<'a>(val: Option<&'a Value>, field: &str, warn: bool) -> Option<Cow<'a, str>> {
+    match val? {
+        Value::String(s) => Some(Cow::Borrowed(s)),
+        _ => {
+            if warn {
+                eprintln!("{} is not a string, skipping transformation.", field);
+            }
+            None
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Reference attributes in vertex documents
+// -----------------------------------------------------------------------------
+
+/// A vertex attribute which contains the key of another vertex or an
+/// array of such keys, given with `--vertex-ref`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct VertexRef {
+    attr: String,
+    // the vertex collection for values without a collection prefix
+    target: String,
+}
+
+/// Parses `<coll>:<attr>[:<targetcoll>]`, the target collection is the
+/// collection itself if it is not given.
+fn parse_vertex_ref(s: &str) -> Option<(String, VertexRef)> {
+    let parts: Vec<&str> = s.split(':').collect();
+    if parts.len() < 2 || parts.len() > 3 || parts.iter().any(|p| p.is_empty()) {
+        return None;
+    }
+    let target = parts.get(2).unwrap_or(&parts[0]);
+    Some((
+        parts[0].to_string(),
+        VertexRef {
+            attr: parts[1].to_string(),
+            target: target.to_string(),
+        },
+    ))
+}
+
+/// Adds the `--vertex-ref` options to their vertex collections.
+fn add_vertex_refs(
+    sub_m: &ArgMatches,
+    vertex_collections: &mut [VertexCollection],
+) -> Result<(), String> {
+    for s in sub_m.get_many::<String>("vertex-ref").unwrap_or_default() {
+        let (coll_name, vertex_ref) = parse_vertex_ref(s).ok_or_else(|| {
+            format!(
+                "Invalid value '{}' for --vertex-ref, use <coll>:<attr>[:<targetcoll>].",
+                s
+            )
+        })?;
+        let coll = vertex_collections
+            .iter_mut()
+            .find(|c| c.coll_name == coll_name)
+            .ok_or_else(|| format!("Unknown vertex collection {} in --vertex-ref.", coll_name))?;
+        coll.references.push(vertex_ref);
+    }
+    Ok(())
+}
+
+/// Counters collected while rewriting the references in one vertex file.
+#[derive(Default)]
+struct RefStats {
+    references: u64,
+    // number of unresolved references by referenced vertex collection
+    unresolved: BTreeMap<String, u64>,
+    // the first unresolved reference, for `UnresolvedPolicy::Error`
+    first_unresolved: Option<String>,
+}
+
+/// Translates a single reference like `_from` and `_to`. A value without
+/// a collection prefix is written without one.
+fn translate_ref(
+    value: &str,
+    target: &str,
+    opts: &EdgeOptions,
+    translation: &Translation,
+    stats: &mut RefStats,
+) -> String {
+    let ep = translate_endpoint(value, target, opts, translation);
+    stats.references += 1;
+    if let Some(coll) = ep.unresolved {
+        *stats.unresolved.entry(coll).or_insert(0) += 1;
+        stats.first_unresolved.get_or_insert_with(|| ep.id.clone());
+    }
+    if value.contains('/') {
+        ep.id
+    } else {
+        ep.id[ep.id.find('/').map_or(0, |p| p + 1)..].to_string()
+    }
+}
+
+/// Translates a JSON reference: a string, a number or an array of them.
+/// Other values and empty strings are left alone, like empty CSV values,
+/// a translated number becomes a string.
+fn translate_ref_value(
+    val: &mut Value,
+    target: &str,
+    opts: &EdgeOptions,
+    translation: &Translation,
+    stats: &mut RefStats,
+) {
+    match val {
+        Value::String(s) if s.is_empty() => {}
+        Value::String(s) => *s = translate_ref(s, target, opts, translation, stats),
+        Value::Number(n) => {
+            let key = n.to_string();
+            let new_key = translate_ref(&key, target, opts, translation, stats);
+            // an unresolved number stays a number
+            if new_key != key {
+                *val = Value::String(new_key);
+            }
+        }
+        Value::Array(values) => {
+            for v in values.iter_mut().filter(|v| v.is_string() || v.is_number()) {
+                translate_ref_value(v, target, opts, translation, stats);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Rewrites the reference attributes of a smartified vertex file in place
+/// with the translation table. In CSV, a value starting with `[` is taken
+/// as a JSON array.
+#[allow(clippy::too_many_arguments)]
+fn rewrite_references(
+    file_name: &str,
+    data_type: DataType,
+    csv: &CsvDialect,
+    header: bool,
+    columns: &[String],
+    refs: &[VertexRef],
+    opts: &EdgeOptions,
     translation: &Translation,
 ) -> i32 {
     println!(
-        "{:.3} Transforming JSON edges in {}",
+        "{:.3} Rewriting references in vertex file {}",
         elapsed(),
-        edge_coll.file_name
+        file_name
     );
-    let in_path = Path::new(&edge_coll.file_name);
-    let edge_file_name_out = edge_coll.file_name.clone() + ".out";
-    let out_path = Path::new(&edge_file_name_out);
-    let input = match File::open(in_path) {
+    let input = match File::open(file_name) {
         Ok(f) => f,
         Err(e) => {
-            eprintln!("Cannot open edge file {}: {}", &edge_coll.file_name, e);
+            eprintln!("Cannot open vertex file {}: {}", file_name, e);
             return 1;
         }
     };
-    let reader = BufReader::new(input);
-    let output = match File::create(&out_path) {
-        Ok(f) => f,
+    let out_name = file_name.to_string() + ".out";
+    let mut out = match File::create(&out_name) {
+        Ok(f) => BufWriter::new(f),
         Err(e) => {
-            eprintln!(
-                "Cannot create temp edge file {}.out: {}",
-                &edge_coll.file_name, e
-            );
+            eprintln!("Cannot create temp vertex file {}: {}", out_name, e);
             return 2;
         }
     };
-    let mut writer = BufWriter::new(output);
+    let mut lines = BufReader::new(input).lines();
+    let mut stats = RefStats::default();
 
-    let mut count = 0usize;
-    for line_result in reader.lines() {
-        let Ok(line_str) = line_result else { continue };
-        let parsed: Value = match serde_json::from_str(&line_str) {
-            Ok(v) => v,
-            Err(e) => {
-                eprintln!(
-                    "JSON parse error in file {}, line {}: {}",
-                    edge_coll.file_name, count, e
-                );
-                continue;
-            }
+    let mut ref_cols = Vec::new();
+    let mut ref_paths = Vec::new();
+    let mut first_line = None;
+    let mut line_nr: u64 = 0;
+    if data_type == DataType::Csv {
+        let Some((col_headers, first)) = read_csv_header(&mut lines, header, columns, csv) else {
+            eprintln!("Could not read header line in vertex file {}", file_name);
+            return 3;
         };
-
-        // We expect an object
-        let mut obj = match parsed {
-            Value::Object(m) => m,
-            _ => {
+        first_line = first;
+        for r in refs {
+            let pos = find_col_pos(&col_headers, &r.attr);
+            if pos < 0 {
                 eprintln!(
-                    "Non-object line in JSON edges file {}, line {}. Skipping.",
-                    edge_coll.file_name, count
+                    "Did not find reference column {} in vertex file {}",
+                    r.attr, file_name
                 );
-                continue;
-            }
-        };
-
-        // fix from/to
-        let (found_from, new_from, from_attr) = fix_json_vertex(
-            &mut obj,
-            "_from",
-            &edge_coll.from_vertex_coll,
-            smart_index,
-            translation,
-        );
-        let (found_to, new_to, to_attr) = fix_json_vertex(
-            &mut obj,
-            "_to",
-            &edge_coll.to_vertex_coll,
-            smart_index,
-            translation,
-        );
-
-        let mut new_key = None;
-        if let (true, Some(fa), true, Some(ta)) =
-            (found_from, from_attr.clone(), found_to, to_attr.clone())
-        {
-            // then we see if _key is present
-            if let Some(Value::String(k)) = obj.get("_key") {
-                if !k.contains(':') {
-                    let triple = format!("{}:{}:{}", fa, k, ta);
-                    new_key = Some(triple);
-                }
+                return 4;
             }
+            ref_cols.push((pos as usize, &r.target));
         }
-
-        // build a new map to preserve order similar to the transform
-        let mut new_map = Map::new();
-        if let Some(k) = new_key {
-            new_map.insert("_key".to_string(), Value::String(k));
-        } else if let Some(v) = obj.get("_key") {
-            new_map.insert("_key".to_string(), v.clone());
-        }
-        if let Some(nf) = new_from {
-            new_map.insert("_from".to_string(), Value::String(nf));
-        } else if let Some(v) = obj.get("_from") {
-            new_map.insert("_from".to_string(), v.clone());
-        }
-        if let Some(nt) = new_to {
-            new_map.insert("_to".to_string(), Value::String(nt));
-        } else if let Some(v) = obj.get("_to") {
-            new_map.insert("_to".to_string(), v.clone());
+        if header {
+            let header_line = col_headers
+                .iter()
+                .map(|c| quote_string(c, csv))
+                .collect::<Vec<String>>()
+                .join(&csv.sep);
+            writeln!(out, "{}", header_line).unwrap();
+            line_nr = 1;
         }
+    } else {
+        ref_paths = refs
+            .iter()
+            .map(|r| (parse_path(&r.attr), &r.target))
+            .collect();
+    }
 
-        // copy the rest
-        for (k, v) in obj.into_iter() {
-            if k != "_key" && k != "_from" && k != "_to" {
-                new_map.insert(k, v);
+    for line_result in first_line.map(Ok).into_iter().chain(lines) {
+        line_nr += 1;
+        let Ok(line_str) = line_result else {
+            continue;
+        };
+        let new_line = if data_type == DataType::Csv {
+            let mut parts = split(&line_str, csv);
+            for (pos, target) in &ref_cols {
+                let Some(field) = parts.get_mut(*pos) else {
+                    continue;
+                };
+                let value = unquote(field, csv);
+                if value.is_empty() {
+                    continue;
+                }
+                let new_value = if value.starts_with('[') {
+                    match serde_json::from_str::<Value>(&value) {
+                        Ok(mut val) => {
+                            translate_ref_value(&mut val, target, opts, translation, &mut stats);
+                            val.to_string()
+                        }
+                        Err(e) => {
+                            eprintln!(
+                                "Invalid JSON array in {} line {}: {}",
+                                file_name, line_nr, e
+                            );
+                            continue;
+                        }
+                    }
+                } else {
+                    translate_ref(&value, target, opts, translation, &mut stats)
+                };
+                *field = quote_string(&new_value, csv);
+            }
+            parts.join(&csv.sep)
+        } else {
+            match serde_json::from_str::<Map<String, Value>>(&line_str) {
+                Ok(mut obj) => {
+                    for (path, target) in &ref_paths {
+                        if let Some(val) = get_path_mut(&mut obj, path) {
+                            translate_ref_value(val, target, opts, translation, &mut stats);
+                        }
+                    }
+                    Value::Object(obj).to_string()
+                }
+                Err(e) => {
+                    eprintln!(
+                        "JSON parse error in file {}, line {}: {}",
+                        file_name, line_nr, e
+                    );
+                    line_str
+                }
+            }
+        };
+        if opts.on_unresolved == UnresolvedPolicy::Error {
+            if let Some(id) = &stats.first_unresolved {
+                eprintln!(
+                    "Unresolved reference {} in {} line {}",
+                    id, file_name, line_nr
+                );
+                drop(out);
+                std::fs::remove_file(&out_name).ok();
+                return 6;
             }
         }
+        writeln!(out, "{}", new_line).unwrap();
+    }
 
-        if let Ok(line_out) = serde_json::to_string(&Value::Object(new_map)) {
-            writeln!(writer, "{}", line_out).unwrap();
-        }
-
-        count += 1;
-        if count % 1_000_000 == 0 {
-            println!(
-                "{:.3} Have transformed {} edges in {} ...",
-                elapsed(),
-                count,
-                edge_coll.file_name
-            );
-        }
+    if let Err(e) = out.flush() {
+        eprintln!("Error flushing temp vertex file {}: {}", out_name, e);
+        return 5;
     }
+    drop(out);
+    std::fs::remove_file(file_name).ok();
+    std::fs::rename(&out_name, file_name).ok();
 
-    if let Err(e) = writer.flush() {
+    let total: u64 = stats.unresolved.values().sum();
+    if total > 0 {
+        let per_coll = stats
+            .unresolved
+            .iter()
+            .map(|(coll, n)| format!("{}: {}", coll, n))
+            .collect::<Vec<String>>()
+            .join(", ");
         eprintln!(
-            "Error flushing edge temp file {}.out: {}",
-            edge_coll.file_name, e
+            "Warning: {} of {} references in {} are unresolved ({}).",
+            total, stats.references, file_name, per_coll
         );
-        return 5;
     }
-    std::fs::remove_file(&edge_coll.file_name).ok();
-    std::fs::rename(&out_path, &in_path).ok();
     println!(
-        "{:.3} Done transforming edges in {}",
+        "{:.3} Rewrote {} references in vertex file {}",
         elapsed(),
-        edge_coll.file_name
+        stats.references,
+        file_name
     );
     0
 }
 
-/// Helper to fix "_from" or "_to" in JSON
-fn fix_json_vertex(
-    obj: &mut Map<String, Value>,
-    field: &str,
-    default_coll: &str,
-    smart_index: i32,
+fn do_edges(
+    opts: &EdgeOptions,
+    vertex_collections: &[VertexCollection],
+    edge_collections: &[EdgeCollection],
+    on_conflict: ConflictPolicy,
+) -> i32 {
+    // Build the translation table from all vertex files, such that we
+    // can look up the smart graph attribute value for each `_from`/`_to`.
+    let mut translation = Translation::default();
+    for coll in vertex_collections {
+        if coll.satellite {
+            translation.add_satellite(&coll.coll_name);
+            continue;
+        }
+        if opts.enterprise {
+            // the keys are not needed, see `enterprise_prefix`
+            translation.collections.insert(coll.coll_name.clone());
+            continue;
+        }
+        let res = translation.load_vertices(coll, opts, on_conflict);
+        if res != 0 {
+            return res;
+        }
+    }
+    translation.report();
+    if let Some(res) = translation.abort_on_conflicts(on_conflict) {
+        return res;
+    }
+    for coll in vertex_collections {
+        if coll.references.is_empty() {
+            continue;
+        }
+        let res = rewrite_references(
+            &coll.file_name,
+            coll.data_type.unwrap_or(opts.data_type),
+            &coll.csv_dialect(&opts.csv),
+            coll.header.unwrap_or(true),
+            coll.columns.as_deref().unwrap_or_default(),
+            &coll.references,
+            opts,
+            &translation,
+        );
+        if res != 0 {
+            return res;
+        }
+    }
+    transform_edges(opts, &translation, edge_collections)
+}
+
+/// Rewrites all edge collections using the given translation table.
+fn transform_edges(
+    opts: &EdgeOptions,
     translation: &Translation,
-) -> (bool, Option<String>, Option<String>) {
-    let val_opt = obj.get(field);
-    if val_opt.is_none() {
-        return (false, None, None);
-    }
-    let val = val_opt.unwrap();
-    if !val.is_string() {
-        eprintln!("{} is not a string, skipping transformation.", field);
-        return (true, None, None);
-    }
-    let old_val = val.as_str().unwrap().to_string();
-
-    // Return the chosen "smart" portion as well
-    let (new_val, att) = if old_val.contains('/') {
-        // see if there's already a colon
-        if old_val.find(':').is_some() {
-            // already transformed
-            (old_val.clone(), None)
-        } else {
-            // check smart_index
-            let slashpos = old_val.find('/').unwrap_or(0);
-            let after_slash = &old_val[slashpos + 1..];
-            if smart_index > 0 && (after_slash.len() as i32) > smart_index {
-                let prefix = &after_slash[..smart_index as usize];
-                let new_str = format!("{}/{}:{}", &old_val[..slashpos], prefix, &after_slash);
-                (new_str, Some(prefix.to_string()))
-            } else {
-                // do we have a translation?
-                if let Some(&idx) = translation.key_tab.get(&old_val) {
-                    let att = translation.smart_attributes[idx as usize].clone();
-                    let key_after_slash = &old_val[slashpos + 1..];
-                    let new_str = format!("{}/{}:{}", &old_val[..slashpos], att, key_after_slash);
-                    (new_str, Some(att))
-                } else {
-                    // keep as is
-                    (old_val.clone(), None)
+    edge_collections: &[EdgeCollection],
+) -> i32 {
+    if !translation.collections.is_empty() {
+        for coll in edge_collections {
+            for v in coll.from_vertex_colls.iter().chain(&coll.to_vertex_colls) {
+                if !translation.collections.contains(v) {
+                    eprintln!(
+                        "Warning: edge file {} uses vertex collection {}, which was not given with --vertices.",
+                        coll.file_name, v
+                    );
                 }
             }
         }
-    } else {
-        // no '/', so add default_coll
-        let full_key = format!("{}/{}", default_coll, old_val);
-        if smart_index > 0 && old_val.len() as i32 > smart_index {
-            let prefix = &old_val[..smart_index as usize];
-            let new_str = format!("{}/{}:{}", default_coll, prefix, &old_val);
-            (new_str, Some(prefix.to_string()))
-        } else {
-            if let Some(&idx) = translation.key_tab.get(&full_key) {
-                let att = translation.smart_attributes[idx as usize].clone();
-                let after_slash = old_val;
-                let new_str = format!("{}/{}:{}", default_coll, att, after_slash);
-                (new_str, Some(att))
-            } else {
-                (full_key, None)
+    }
+
+    let mut total = EdgeStats::default();
+    for coll in edge_collections {
+        let mut stats = EdgeStats::default();
+        let opts = coll.options(opts);
+        let res = match opts.data_type {
+            DataType::Csv => transform_edges_csv(coll, &opts, translation, &mut stats),
+            DataType::Jsonl => transform_edges_jsonl(coll, &opts, translation, &mut stats),
+        };
+        stats.report(&coll.file_name);
+        total.add(&stats);
+        if res != 0 {
+            if res == 6 {
+                eprintln!("Aborting because of unresolved endpoints.");
+            }
+            if res == 7 {
+                eprintln!(
+                    "Aborting because of an edge between different smart graph attribute values."
+                );
             }
+            return res;
         }
-    };
+    }
+    if edge_collections.len() > 1 {
+        total.report("all edge files");
+    }
 
-    (true, Some(new_val), att)
+    0
 }
 
-fn do_edges(
-    data_type: DataType,
-    sep: char,
-    quo: char,
+// ------------------------------------------------------------
+// do_graph: smartify vertices and edges in a single invocation
+// ------------------------------------------------------------
+
+/// Copies the vertices of a satellite collection to the output file, they
+/// are not smartified.
+fn copy_satellite(coll: &VertexCollection, output_file: &str, opts: &VertexOptions) -> i32 {
+    println!(
+        "{:.3} Copying vertices of satellite collection {} from {} to {}",
+        elapsed(),
+        coll.coll_name,
+        coll.file_name,
+        output_file
+    );
+    if opts.output.data_type.is_some_and(|t| t != opts.data_type) {
+        eprintln!(
+            "Warning: satellite collection {} is copied unchanged, ignoring the output type.",
+            coll.coll_name
+        );
+    }
+    if let Err(e) = std::fs::copy(&coll.file_name, output_file) {
+        eprintln!(
+            "Cannot copy vertex file {} to {}: {}",
+            coll.file_name, output_file, e
+        );
+        return 1;
+    }
+    0
+}
+
+fn do_graph(
+    vertex_opts: &VertexOptions,
+    edge_opts: &EdgeOptions,
     vertex_collections: &[VertexCollection],
     edge_collections: &[EdgeCollection],
-    smart_index: i32,
+    on_conflict: ConflictPolicy,
 ) -> i32 {
-    // In the original C++ code, there's logic about reading vertex files,
-    // building a "Translation" table, then processing edges in chunks.
-    // Here, for simplicity, we will assume no vertex data or we have
-    // no new lines to read for them. We'll provide an empty or default
-    // translation.
-
-    let translation = Translation::default();
-    // If you had logic to fill `translation` from vertex files, you'd do it here.
-
-    for coll in edge_collections {
-        let res = match data_type {
-            DataType::CSV => transform_edges_csv(coll, sep, quo, smart_index, &translation),
-            DataType::JSONL => transform_edges_jsonl(coll, smart_index, &translation),
+    // Transform all vertex files and capture the new keys on the way,
+    // such that we need not read the output again for the edges.
+    let mut translation = Translation::default();
+    for coll in vertex_collections {
+        let Some(output_file) = &coll.output_file else {
+            eprintln!(
+                "No output file given for vertex collection {}, use <coll>:<input>:<output>.",
+                coll.coll_name
+            );
+            return 1;
+        };
+        if *output_file == coll.file_name {
+            eprintln!(
+                "Output file {} must be different from the input file.",
+                output_file
+            );
+            return 1;
+        }
+        if coll.satellite {
+            let res = copy_satellite(coll, output_file, &coll.options(vertex_opts));
+            if res != 0 {
+                return res;
+            }
+            translation.add_satellite(&coll.coll_name);
+            continue;
+        }
+        println!(
+            "{:.3} Transforming vertices of collection {} from {} to {}",
+            elapsed(),
+            coll.coll_name,
+            coll.file_name,
+            output_file
+        );
+        let capture = KeyCapture {
+            translation: &mut translation,
+            coll_name: &coll.coll_name,
+            policy: on_conflict,
+        };
+        let opts = coll.options(vertex_opts);
+        let res = do_vertices(&coll.file_name, output_file, &opts, Some(capture));
+        if res != 0 {
+            return res;
+        }
+    }
+    translation.report();
+    if let Some(res) = translation.abort_on_conflicts(on_conflict) {
+        return res;
+    }
+    // The references can only be rewritten once all keys are known, this
+    // is done in the output files.
+    for coll in vertex_collections {
+        let (Some(output_file), false) = (&coll.output_file, coll.references.is_empty()) else {
+            continue;
         };
+        let opts = coll.options(vertex_opts);
+        let res = rewrite_references(
+            output_file,
+            opts.output.data_type.unwrap_or(opts.data_type),
+            &opts.csv,
+            true,
+            &[],
+            &coll.references,
+            edge_opts,
+            &translation,
+        );
         if res != 0 {
             return res;
         }
     }
+    transform_edges(edge_opts, &translation, edge_collections)
+}
 
-    0
+// ----------------------------------------------------------------
+// do_smartjoin: prepare a child collection for SmartJoins
+// ----------------------------------------------------------------
+
+/// Looks up the smart graph attribute value of a parent document in the
+/// translation table built from the parent collections.
+struct ParentLookup {
+    translation: Translation,
+    opts: EdgeOptions,
+    parent_coll: String,
+    // number of parents which were not found and got the default value
+    unresolved: Cell<u64>,
+}
+
+impl ParentLookup {
+    /// Returns the smart graph attribute value for a parent key, which may
+    /// have a collection prefix. Returns `None` if the parent is not found
+    /// and no default value is given with `--on-unresolved`.
+    fn lookup(&self, parent: &str) -> Option<String> {
+        let ep = translate_endpoint(parent, &self.parent_coll, &self.opts, &self.translation);
+        if ep.unresolved.is_some() && ep.att.is_some() {
+            self.unresolved.set(self.unresolved.get() + 1);
+        }
+        ep.att
+    }
+}
+
+fn do_smartjoin(
+    input_file: &str,
+    output_file: &str,
+    mut opts: VertexOptions,
+    parent_opts: EdgeOptions,
+    parents: &[VertexCollection],
+    parent_coll: &str,
+) -> i32 {
+    let mut translation = Translation::default();
+    for coll in parents {
+        let res = translation.load_vertices(coll, &parent_opts, ConflictPolicy::FirstWins);
+        if res != 0 {
+            return res;
+        }
+    }
+    translation.report();
+    let lookup = Rc::new(ParentLookup {
+        translation,
+        opts: parent_opts,
+        parent_coll: parent_coll.to_string(),
+        unresolved: Cell::new(0),
+    });
+    opts.parent_lookup = Some(lookup.clone());
+    println!(
+        "{:.3} Transforming documents from {} to {}",
+        elapsed(),
+        input_file,
+        output_file
+    );
+    let res = do_vertices(input_file, output_file, &opts, None);
+    let unresolved = lookup.unresolved.get();
+    if unresolved > 0 {
+        eprintln!(
+            "Warning: {} documents have an unknown parent, using the default smart value.",
+            unresolved
+        );
+    }
+    res
 }